@@ -16,12 +16,28 @@ let
     };
   axis_code = with lib.types; enum (import ./axis_codes.nix);
   bus_type = with lib.types; enum (import ./bus_types.nix);
+  prop_type =
+    with lib.types;
+    enum [
+      "POINTER"
+      "DIRECT"
+      "BUTTONPAD"
+      "SEMI_MT"
+      "TOPBUTTONPAD"
+      "POINTING_STICK"
+      "ACCELEROMETER"
+    ];
   action =
     with lib.types;
     let
       simple = enum [
         "None"
         "ToggleScroll"
+        "HoldScroll"
+        "CycleSpeed"
+        "SpeedUp"
+        "SpeedDown"
+        "ResetSpeed"
       ];
       button = mkOptionType {
         name = "button";
@@ -31,31 +47,139 @@ let
       };
       lock = mkOptionType {
         name = "lock";
-        description = "{ ToggleLock = [ key_code ] }";
+        description = "{ ToggleLock = [ key_code ] } or { ToggleLock = { buttons = [ key_code ], idle_timeout = duration, release_on_other_button = bool, release_on_stop = duration } }";
         check =
-          x: lib.length (lib.attrNames x) == 1 && x ? ToggleLock && (listOf key_code).check x.ToggleLock;
+          x:
+          lib.length (lib.attrNames x) == 1
+          && x ? ToggleLock
+          && (
+            (listOf key_code).check x.ToggleLock
+            || (lib.isAttrs x.ToggleLock && x.ToggleLock ? buttons && (listOf key_code).check x.ToggleLock.buttons)
+          );
         merge = lib.options.mergeEqualOption;
       };
       gesture = mkOptionType {
         name = "gesture";
-        description = "{ Gesture = { \"gesture_key\" = action } }, where gesture_key is a sequence of U, D, L, R";
+        description = "{ Gesture = { \"gesture_key\" = action } }, where gesture_key is a sequence of U, D, L, R, each optionally qualified with :short/:long and :slow/:fast and followed by + for one or more strokes, and * matches any strokes (movement in the same direction continues a stroke, so adjacent strokes in the same direction are rejected); or @name to match the shape template name (built-in circle_cw, circle_ccw, check, zigzag, or recorded with RecordShape)";
         check =
           x: lib.length (lib.attrNames x) == 1 && x ? Gesture && (lib.types.attrsOf action).check x.Gesture;
         merge = lib.options.mergeEqualOption;
       };
+      continuousGesture = mkOptionType {
+        name = "continuousGesture";
+        description = "{ ContinuousGesture = { U = step, D = step, L = step, R = step } }, where step is { step = int, action = action } and every direction is optional";
+        check =
+          x:
+          lib.length (lib.attrNames x) == 1
+          && x ? ContinuousGesture
+          && lib.isAttrs x.ContinuousGesture
+          && lib.all (
+            step: lib.isAttrs step && step ? step && lib.isInt step.step && step ? action && action.check step.action
+          ) (lib.attrValues x.ContinuousGesture);
+        merge = lib.options.mergeEqualOption;
+      };
       slow = mkOptionType {
         name = "slow";
         description = "{ ToggleSlow = float }";
         check = x: lib.length (lib.attrNames x) == 1 && x ? ToggleSlow && lib.types.num.check x.ToggleSlow;
         merge = lib.options.mergeEqualOption;
       };
+      sequence = mkOptionType {
+        name = "sequence";
+        description = "{ Sequence = { steps = [ step ], repeat = int, on_busy = \"Restart\" | \"Ignore\" | \"Stop\" } }, where step is one of { Action = action }, { Down = key_code }, { Up = key_code }, { Delay = duration }";
+        check =
+          x:
+          lib.length (lib.attrNames x) == 1
+          && x ? Sequence
+          && x.Sequence ? steps
+          && lib.isList x.Sequence.steps;
+        merge = lib.options.mergeEqualOption;
+      };
+      autofire = mkOptionType {
+        name = "autofire";
+        description = "{ Autofire = { button = key_code, interval = duration, delay = duration } }";
+        check =
+          x:
+          lib.length (lib.attrNames x) == 1
+          && x ? Autofire
+          && x.Autofire ? button
+          && key_code.check x.Autofire.button
+          && x.Autofire ? interval;
+        merge = lib.options.mergeEqualOption;
+      };
+      conditional = mkOptionType {
+        name = "conditional";
+        description = "{ If = { cond = condition, then = action, else = action } }, where condition is one of \"ScrollActive\", \"SlowActive\", \"LockEngaged\", \"ButtonLocked\", \"GestureActive\", \"SequenceRunning\", { Not = condition }, { All = [ condition ] }, { Any = [ condition ] }";
+        check =
+          x:
+          lib.length (lib.attrNames x) == 1
+          && x ? If
+          && x.If ? cond
+          && x.If ? "then"
+          && action.check x.If."then"
+          && (x.If ? "else" -> action.check x.If."else");
+        merge = lib.options.mergeEqualOption;
+      };
+      toggle = mkOptionType {
+        name = "toggle";
+        description = "{ Toggle = { on = action, off = action } }";
+        check =
+          x:
+          lib.length (lib.attrNames x) == 1
+          && x ? Toggle
+          && x.Toggle ? on
+          && x.Toggle ? off
+          && action.check x.Toggle.on
+          && action.check x.Toggle.off;
+        merge = lib.options.mergeEqualOption;
+      };
+      setScroll = mkOptionType {
+        name = "setScroll";
+        description = "{ SetScroll = bool }";
+        check = x: lib.length (lib.attrNames x) == 1 && x ? SetScroll && lib.isBool x.SetScroll;
+        merge = lib.options.mergeEqualOption;
+      };
+      setSlow = mkOptionType {
+        name = "setSlow";
+        description = "{ SetSlow = float }";
+        check = x: lib.length (lib.attrNames x) == 1 && x ? SetSlow && lib.types.num.check x.SetSlow;
+        merge = lib.options.mergeEqualOption;
+      };
+      recordShape = mkOptionType {
+        name = "recordShape";
+        description = "{ RecordShape = str }";
+        check = x: lib.length (lib.attrNames x) == 1 && x ? RecordShape && lib.isString x.RecordShape;
+        merge = lib.options.mergeEqualOption;
+      };
+      layer = mkOptionType {
+        name = "layer";
+        description = "{ HoldLayer = str } or { ToggleLayer = str } or { OneShotLayer = str }";
+        check =
+          x:
+          lib.length (lib.attrNames x) == 1
+          && lib.any (k: x ? ${k} && lib.isString x.${k}) [
+            "HoldLayer"
+            "ToggleLayer"
+            "OneShotLayer"
+          ];
+        merge = lib.options.mergeEqualOption;
+      };
     in
     oneOf [
       simple
       button
       lock
       gesture
+      continuousGesture
       slow
+      sequence
+      autofire
+      conditional
+      toggle
+      setScroll
+      setSlow
+      layer
+      recordShape
     ];
   axisDef =
     with lib.types;
@@ -72,6 +196,44 @@ let
           default = 1.0;
           example = 0.1;
         };
+        acceleration = mkOption {
+          type = nullOr (submodule {
+            options = {
+              low_speed = mkOption {
+                type = either int float;
+                description = "Axis speed in counts per second at and below which the min multiplier applies";
+                default = 0;
+              };
+              high_speed = mkOption {
+                type = either int float;
+                description = "Axis speed in counts per second at and above which the max multiplier applies";
+                example = 3000;
+              };
+              min = mkOption {
+                type = either int float;
+                description = "Multiplier for slow movement";
+                default = 1.0;
+              };
+              max = mkOption {
+                type = either int float;
+                description = "Multiplier for fast movement";
+                example = 4.0;
+              };
+              exponent = mkOption {
+                type = either int float;
+                description = "Shape of the curve between min and max, 1 is linear";
+                default = 1.0;
+              };
+              window = mkOption {
+                type = str;
+                description = "Window to average the axis speed over (with suffix s/ms/&c)";
+                default = "100ms";
+              };
+            };
+          });
+          description = "Scale movement by how fast the axis moves, meant for mapping to scroll axes";
+          default = null;
+        };
       };
     };
   filterNull =
@@ -132,6 +294,17 @@ in
       '';
       default = null;
     };
+    socketActivation = mkOption {
+      type = bool;
+      description = "Create the control socket with a systemd socket unit, so that clients can connect before the daemon is up";
+      default = false;
+    };
+    watchdogSec = mkOption {
+      type = nullOr str;
+      description = "Systemd watchdog timeout; the service is restarted if the main loop stops responding for this long";
+      example = "10s";
+      default = null;
+    };
     settingsFile = mkOption {
       type = oneOf [
         path
@@ -142,9 +315,14 @@ in
       default = config_toml;
     };
     settings = {
+      socket_enabled = mkOption {
+        type = bool;
+        description = "Enable the control socket";
+        default = true;
+      };
       socket_path = mkOption {
         type = nullOr path;
-        description = "Path to the control socket";
+        description = "Path to the control socket. Defaults to $XDG_RUNTIME_DIR/tweakpoint.sock";
         example = "/tmp/tweakpoint.sock";
         default = null;
       };
@@ -154,39 +332,65 @@ in
         example = "/dev/input/by-id/usb-Foo-Bar";
       };
       btn_map = mkOption {
-        type = attrsOf key_code;
-        description = "Map buttons to other buttons";
+        type = attrsOf (either key_code action);
+        description = "Map buttons to other buttons or actions";
         example = {
           BTN_MIDDLE = "BTN_LEFT";
           BTN_SIDE = "BTN_TASK";
+          BTN_EXTRA.Autofire = {
+            button = "BTN_LEFT";
+            interval = "100ms";
+          };
         };
         default = { };
       };
+      inherit_identity = mkOption {
+        type = bool;
+        description = "Copy name and ids of the physical device, so libinput quirks and hwdb entries keep applying. Options set below still take precedence";
+        default = false;
+      };
+      name_suffix = mkOption {
+        type = nullOr str;
+        description = "Appended to the inherited name";
+        default = null;
+        example = " (tweakpoint)";
+      };
       name = mkOption {
-        type = str;
-        description = "Human-readable name of the virtual pointer device";
-        default = "tweakpoint";
+        type = nullOr str;
+        description = "Human-readable name of the virtual pointer device. Defaults to tweakpoint, unless inherited";
+        default = null;
         example = "tweakpoint";
       };
       vendor_id = mkOption {
-        type = ints.u16;
-        description = "Reported vendor_id of the virtual pointer device";
-        default = 1;
+        type = nullOr ints.u16;
+        description = "Reported vendor_id of the virtual pointer device. Defaults to 1, unless inherited";
+        default = null;
       };
       product_id = mkOption {
-        type = ints.u16;
-        description = "Reported product_id of the virtual pointer device";
-        default = 1;
+        type = nullOr ints.u16;
+        description = "Reported product_id of the virtual pointer device. Defaults to 1, unless inherited";
+        default = null;
       };
       product_version = mkOption {
-        type = ints.u16;
-        description = "Reported product version of the virtual pointer device";
-        default = 1;
+        type = nullOr ints.u16;
+        description = "Reported product version of the virtual pointer device. Defaults to 1, unless inherited";
+        default = null;
       };
       bus = mkOption {
-        type = bus_type;
-        description = "Reported bus type of the virtual pointer device";
-        default = "BUS_USB";
+        type = nullOr bus_type;
+        description = "Reported bus type of the virtual pointer device. Defaults to BUS_USB, unless inherited";
+        default = null;
+      };
+      properties = mkOption {
+        type = listOf prop_type;
+        description = "Input properties of the virtual pointer device";
+        default = [ "POINTER" ];
+        example = [ "POINTING_STICK" ];
+      };
+      keys_name = mkOption {
+        type = str;
+        description = "Human-readable name of the virtual keyboard emitting KEY_* codes, created when needed";
+        default = "tweakpoint keys";
       };
       axis_map = {
         regular = mkOption {
@@ -212,6 +416,64 @@ in
           };
           default = { };
         };
+        held = mkOption {
+          type = attrsOf (attrsOf axisDef);
+          description = "Axis maps that apply while a physical button is held, over the other tables. The button is still pressed as usual; map it to \"None\" in btn_map to only remap the axes";
+          example = {
+            BTN_SIDE.REL_Y = {
+              axis = "REL_HWHEEL_HI_RES";
+              factor = 10.0;
+            };
+          };
+          default = { };
+        };
+        chord = mkOption {
+          type = attrsOf (attrsOf axisDef);
+          description = "Axis maps that apply while a chord of the meta key and a button is held, by the chorded button, which needs a meta.chord entry (\"None\" will do)";
+          example = {
+            BTN_EXTRA.REL_WHEEL.axis = "REL_HWHEEL";
+          };
+          default = { };
+        };
+      };
+      axis_triggers = mkOption {
+        type = attrsOf (submodule {
+          options = {
+            threshold = mkOption {
+              type = ints.positive;
+              description = "Counts of movement per action, 1 is a wheel detent or tilt wheel click";
+              default = 1;
+            };
+            positive = mkOption {
+              type = nullOr action;
+              description = "Action for movement in the positive direction";
+              default = null;
+            };
+            negative = mkOption {
+              type = nullOr action;
+              description = "Action for movement in the negative direction";
+              default = null;
+            };
+            repeat = mkOption {
+              type = bool;
+              description = "Run the action for every threshold crossed while held, instead of once per hold";
+              default = false;
+            };
+            hold_timeout = mkOption {
+              type = str;
+              description = "Axis events further apart than this start a new hold (with suffix s/ms/&c)";
+              default = "200ms";
+            };
+          };
+        });
+        description = "Run actions on relative axis movement, e.g. tilt wheel clicks. Axis events in a direction with an action don't reach the system";
+        example = {
+          REL_HWHEEL = {
+            positive.Button = "BTN_FORWARD";
+            negative.Button = "BTN_BACK";
+          };
+        };
+        default = { };
       };
       hi_res_enabled = mkOption {
         type = bool;
@@ -228,6 +490,158 @@ in
         description = "Pass through movement during gesture";
         default = true;
       };
+      gesture = {
+        max_duration = mkOption {
+          type = nullOr str;
+          description = "Cancel gestures held longer than this (with suffix s/ms/&c)";
+          example = "2s";
+          default = null;
+        };
+        max_strokes = mkOption {
+          type = nullOr ints.unsigned;
+          description = "Cancel gestures with more strokes than this";
+          example = 4;
+          default = null;
+        };
+        cancel_on_button = mkOption {
+          type = bool;
+          description = "Cancel the gesture when another button is pressed, swallowing that button";
+          default = false;
+        };
+        max_edit_distance = mkOption {
+          type = nullOr ints.unsigned;
+          description = "If no gesture key matches, use the closest key without + or * at most this many strokes off";
+          example = 1;
+          default = null;
+        };
+        long_stroke = mkOption {
+          type = nullOr ints.u32;
+          description = "Strokes at least this many counts long match :long, shorter ones :short";
+          example = 300;
+          default = null;
+        };
+        fast_stroke = mkOption {
+          type = nullOr (either int float);
+          description = "Strokes at least this many counts per second match :fast, slower ones :slow";
+          example = 2000;
+          default = null;
+        };
+        min_shape_score = mkOption {
+          type = float;
+          description = "Minimum score between 0 and 1 for a @shape gesture key to match";
+          default = 0.85;
+        };
+        shapes = mkOption {
+          type = nullOr str;
+          description = "File with shape templates recorded by RecordShape, relative to the config file. The generated config lives in the read-only nix store, so set a writable absolute path to record shapes";
+          example = "/home/user/.config/tweakpoint/gesture-shapes.toml";
+          default = null;
+        };
+        cancel_on_return = mkOption {
+          type = nullOr ints.u32;
+          description = "Cancel the gesture when the pointer comes back within this many counts of where it started";
+          example = 20;
+          default = null;
+        };
+      };
+      keyboard = mkOption {
+        type = nullOr (submodule {
+          options = {
+            device = mkOption {
+              type = path;
+              description = "Path to the secondary keyboard input event device file";
+              example = "/dev/input/by-id/usb-Foo-Bar-event-kbd";
+            };
+            grab = mkOption {
+              type = bool;
+              description = "Grab the keyboard and pass unhandled keys through a separate virtual keyboard. If disabled, handled keys also reach the system as usual";
+              default = true;
+            };
+            name = mkOption {
+              type = str;
+              description = "Human-readable name of the virtual passthrough keyboard device";
+              default = "tweakpoint keyboard";
+            };
+            bind = mkOption {
+              type = attrsOf action;
+              description = "Keyboard keys that run an action while held";
+              example = {
+                KEY_CAPSLOCK = "HoldScroll";
+              };
+              default = { };
+            };
+            forward = mkOption {
+              type = listOf key_code;
+              description = "Keyboard keys handled as pointer buttons, e.g. to use as the meta key or chord triggers. Only used if the keyboard is grabbed, as the system would otherwise see these keys twice";
+              example = [ "KEY_LEFTMETA" ];
+              default = [ ];
+            };
+          };
+        });
+        description = "Secondary keyboard, whose keys can drive tweakpoint actions";
+        default = null;
+      };
+      persist = {
+        scroll = mkOption {
+          type = bool;
+          description = "Restore scroll mode across restarts, from $XDG_STATE_HOME/tweakpoint/state.toml";
+          default = false;
+        };
+        slow = mkOption {
+          type = bool;
+          description = "Restore pointer speed and speed level across restarts, from $XDG_STATE_HOME/tweakpoint/state.toml";
+          default = false;
+        };
+      };
+      layers = mkOption {
+        type = attrsOf (submodule {
+          options = {
+            btn_map = mkOption {
+              type = attrsOf (either key_code action);
+              description = "Button mappings overriding the ones below this layer";
+              default = { };
+            };
+            chord = mkOption {
+              type = attrsOf action;
+              description = "Chord actions overriding the ones below this layer";
+              default = { };
+            };
+          };
+        });
+        description = "Named button layers, activated with the HoldLayer, ToggleLayer and OneShotLayer actions";
+        example = {
+          nav.btn_map = {
+            BTN_SIDE = "BTN_BACK";
+            BTN_EXTRA = "BTN_FORWARD";
+          };
+        };
+        default = { };
+      };
+      click_lock = mkOption {
+        type = attrsOf str;
+        description = "Buttons that latch when held longer than the given time (with suffix s/ms/&c), and are released by the next click";
+        example = {
+          BTN_LEFT = "1s";
+        };
+        default = { };
+      };
+      speed_levels = mkOption {
+        type = listOf (either int float);
+        description = "Pointer speed factors for CycleSpeed, SpeedUp and SpeedDown actions";
+        example = [
+          1.0
+          0.5
+          0.25
+          0.1
+        ];
+        default = [ ];
+      };
+      autofire_max_rate = mkOption {
+        type = nullOr (either int float);
+        description = "Upper limit on autofire clicks per second";
+        example = 20;
+        default = null;
+      };
       meta = {
         key = mkOption {
           type = key_code;
@@ -267,6 +681,15 @@ in
     };
   };
   config = lib.mkIf conf.enable {
+    systemd.user.sockets.tweakpoint = lib.mkIf (conf.socketActivation && conf.settings.socket_enabled) {
+      Install.WantedBy = [ "sockets.target" ];
+      Socket = {
+        ListenStream =
+          if conf.settings.socket_path != null then conf.settings.socket_path else "%t/tweakpoint.sock";
+        FileDescriptorName = "tweakpoint";
+        SocketMode = "0600";
+      };
+    };
     systemd.user.services.tweakpoint = {
       Install.WantedBy = [ "default.target" ];
       Service = {
@@ -280,11 +703,15 @@ in
           )
         }";
         Type = "notify";
+        ExecReload = "${lib.getExe' pkgs.coreutils "kill"} -HUP $MAINPID";
         ExecStart = "${
           lib.getExe' self.packages.${pkgs.system}.default "tweakpoint"
         } --config ${config_toml}";
         Restart = "always";
       }
+      // lib.optionalAttrs (conf.watchdogSec != null) {
+        WatchdogSec = conf.watchdogSec;
+      }
       // (
         if (conf.postScript != null) then
           {
@@ -1,119 +1,27 @@
 //! Waybar sample client
 
-use std::{collections::BTreeMap, io::Read, time::Duration};
+use std::{path::PathBuf, time::Duration};
 
-use evdev::KeyCode;
-
-#[derive(Debug)]
-enum Step {
-    Released,
-    Locked,
-    WillRelease,
-}
-
-#[derive(Debug)]
-pub enum GestureDir {
-    U,
-    D,
-    L,
-    R,
-}
-
-#[derive(Debug)]
-struct State {
-    scroll_active: bool,
-    buttons: BTreeMap<KeyCode, Step>,
-    gesture: Vec<GestureDir>,
-    slow: f64,
-}
-
-impl State {
-    fn from_bytes(ptr: &mut &[u8]) -> anyhow::Result<Self> {
-        fn with_len<R>(
-            ptr: &mut &[u8],
-            action: impl FnOnce(&mut &[u8]) -> anyhow::Result<R>,
-        ) -> anyhow::Result<R> {
-            let mut len = [0u8; 4];
-            ptr.read_exact(&mut len)?;
-            let len = u32::from_le_bytes(len);
-            let (mut cur, rest) = ptr.split_at(len as usize);
-            *ptr = rest;
-            action(&mut cur)
-        }
-        let mut scroll_active = [0u8];
-        ptr.read_exact(&mut scroll_active)?;
-        let scroll_active = scroll_active[0] != 0;
-        let buttons = with_len(ptr, |ptr| {
-            let mut buttons = BTreeMap::new();
-            while !ptr.is_empty() {
-                let mut key_code = [0u8; 2];
-                ptr.read_exact(&mut key_code)?;
-                let key_code = evdev::KeyCode::new(u16::from_le_bytes(key_code));
-                let mut state = [0u8];
-                ptr.read_exact(&mut state)?;
-                let state = state[0];
-                buttons.insert(
-                    key_code,
-                    match state {
-                        b'R' => Step::Released,
-                        b'L' => Step::Locked,
-                        b'W' => Step::WillRelease,
-                        _ => {
-                            eprintln!("Unexpected button state for {key_code:?}: {state}");
-                            continue;
-                        }
-                    },
-                );
-            }
-            Ok(buttons)
-        })?;
-        let gesture = with_len(ptr, |ptr| {
-            let mut gesture = Vec::new();
-            while !ptr.is_empty() {
-                let mut byte = [0u8];
-                ptr.read_exact(&mut byte)?;
-                let byte = byte[0];
-                gesture.push(match byte {
-                    b'U' => GestureDir::U,
-                    b'D' => GestureDir::D,
-                    b'L' => GestureDir::L,
-                    b'R' => GestureDir::R,
-                    _ => {
-                        eprintln!("Unexpected gesture direction {byte}");
-                        continue;
-                    }
-                });
-            }
-            Ok(gesture)
-        })?;
-        let mut slow_bytes = [0; 8];
-        ptr.read_exact(&mut slow_bytes)?;
-        let slow = f64::from_le_bytes(slow_bytes);
-        Ok(Self {
-            scroll_active,
-            buttons,
-            gesture,
-            slow,
-        })
-    }
-}
+use tweakpoint::{
+    client::{BlockingSubscriber, default_socket_path},
+    protocol::{GestureDir, GestureResult, LockStep},
+};
 
 fn main_loop() -> anyhow::Result<()> {
-    let mut socket = std::os::unix::net::UnixStream::connect(
-        std::env::args()
-            .nth(1)
-            .unwrap_or("/tmp/tweakpoint.sock".to_owned()),
-    )?;
-    let mut size = [0; 4];
+    let path = match std::env::args_os().nth(1) {
+        Some(path) => PathBuf::from(path),
+        None => default_socket_path().unwrap_or_else(|| "/tmp/tweakpoint.sock".into()),
+    };
+    let mut subscriber = BlockingSubscriber::connect(path)?;
     loop {
-        socket.read_exact(&mut size)?;
-        let mut buf = vec![0; u32::from_le_bytes(size) as usize];
-        socket.read_exact(buf.as_mut_slice())?;
-        let ptr = &mut buf.as_slice();
-        let state = State::from_bytes(ptr)?;
+        let state = subscriber.recv()?;
         let scroll_lock = if state.scroll_active { "󰆾" } else { "" };
         let btn_lock = if !state.buttons.is_empty() {
-            if state.buttons.values().any(|x| matches!(x, Step::Locked)) {
+            if state
+                .buttons
+                .values()
+                .any(|x| matches!(x, LockStep::Locked))
+            {
                 "󱕐"
             } else {
                 "󱕑"
@@ -131,16 +39,42 @@ fn main_loop() -> anyhow::Result<()> {
                 GestureDir::R => "→",
             })
             .collect::<String>();
-        let slow = if state.slow != 1.0 {
-            format!(" 󰾆 {}", state.slow)
-        } else {
-            String::new()
+        let slow = match state.speed_level {
+            Some(level) => format!(" 󰾆 {} ({})", state.slow, level + 1),
+            None if state.slow != 1.0 => format!(" 󰾆 {}", state.slow),
+            None => String::new(),
+        };
+        let gesture_class = match state.last_gesture {
+            Some(GestureResult::Executed) => r#", "gesture-executed""#,
+            Some(GestureResult::Unmatched) => r#", "gesture-unmatched""#,
+            Some(GestureResult::Cancelled) => r#", "gesture-cancelled""#,
+            None => "",
         };
+        let tooltip = state
+            .candidates
+            .iter()
+            .map(|x| format!("{} : {}", x.key, x.action))
+            .collect::<Vec<_>>()
+            .join("\n");
         let text = format!("{scroll_lock}{btn_lock}{slow}{gesture}");
-        println!(r#"{{ "text": "{text}", "class": "tweakpoint" }}"#,);
+        println!(
+            r#"{{ "text": "{text}", "tooltip": "{}", "class": ["tweakpoint"{gesture_class}] }}"#,
+            json_escape(&tooltip),
+        );
     }
 }
 
+fn json_escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '"' => "\\\"".to_owned(),
+            '\\' => "\\\\".to_owned(),
+            c if c.is_control() => format!("\\u{:04x}", c as u32),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
 fn main() -> ! {
     loop {
         if let Err(e) = main_loop() {
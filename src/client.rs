@@ -0,0 +1,90 @@
+//! Clients for the state socket.
+
+use std::{
+    io::{Read, Result, Write},
+    path::{Path, PathBuf},
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::protocol::{Command, State, frame_len};
+
+/// `$XDG_RUNTIME_DIR/tweakpoint.sock`, if `XDG_RUNTIME_DIR` is set.
+pub fn default_socket_path() -> Option<PathBuf> {
+    Some(PathBuf::from(std::env::var_os("XDG_RUNTIME_DIR")?).join("tweakpoint.sock"))
+}
+
+/// Receives state updates from the daemon.
+pub struct Subscriber {
+    stream: tokio::net::UnixStream,
+    buf: Vec<u8>,
+}
+
+impl Subscriber {
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            stream: tokio::net::UnixStream::connect(path).await?,
+            buf: vec![],
+        })
+    }
+
+    /// Wait for the next state update. The first one is sent on connect.
+    pub async fn recv(&mut self) -> Result<State> {
+        let mut len = [0; 4];
+        self.stream.read_exact(&mut len).await?;
+        self.buf.resize(frame_len(len), 0);
+        self.stream.read_exact(&mut self.buf).await?;
+        State::decode(&self.buf)
+    }
+}
+
+/// Blocking version of [`Subscriber`].
+pub struct BlockingSubscriber {
+    stream: std::os::unix::net::UnixStream,
+    buf: Vec<u8>,
+}
+
+impl BlockingSubscriber {
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            stream: std::os::unix::net::UnixStream::connect(path)?,
+            buf: vec![],
+        })
+    }
+
+    /// Wait for the next state update. The first one is sent on connect.
+    pub fn recv(&mut self) -> Result<State> {
+        let mut len = [0; 4];
+        self.stream.read_exact(&mut len)?;
+        self.buf.resize(frame_len(len), 0);
+        self.stream.read_exact(&mut self.buf)?;
+        State::decode(&self.buf)
+    }
+}
+
+/// Sends commands to the daemon.
+pub struct CommandSender {
+    stream: tokio::net::UnixStream,
+}
+
+impl CommandSender {
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            stream: tokio::net::UnixStream::connect(path).await?,
+        })
+    }
+
+    pub async fn send(&mut self, command: &Command) -> Result<()> {
+        let mut buf = vec![];
+        command.encode(&mut buf);
+        self.stream.write_all(&buf).await
+    }
+
+    /// Blocking version of [`CommandSender::connect`] and
+    /// [`CommandSender::send`] for one-off commands.
+    pub fn send_blocking(path: impl AsRef<Path>, command: &Command) -> Result<()> {
+        let mut buf = vec![];
+        command.encode(&mut buf);
+        std::os::unix::net::UnixStream::connect(path)?.write_all(&buf)
+    }
+}
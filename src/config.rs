@@ -1,39 +1,444 @@
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    num::NonZeroU32,
     path::PathBuf,
+    sync::Arc,
     time::Duration,
 };
 
-use evdev::{BusType, EventType, InputEvent, KeyCode, RelativeAxisCode};
-use serde::{Deserialize, Serialize};
+use evdev::{BusType, Device, EventType, InputEvent, InputId, KeyCode, PropType, RelativeAxisCode};
+use serde::{Deserialize, Deserializer, Serialize, de::Error as _};
 use smart_default::SmartDefault;
+use tweakpoint::protocol::LockStep;
 
-use crate::{state::State, utils::IteratorExt};
+use crate::{
+    gesture::{GesturePattern, PatternError, Stroke},
+    state::{ActionKey, ContinuousGestureState, State},
+    utils::IteratorExt,
+};
 
 #[derive(Serialize, Deserialize, SmartDefault)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
+    #[default(true)]
+    pub socket_enabled: bool,
+    /// Defaults to `$XDG_RUNTIME_DIR/tweakpoint.sock`. Ignored if the socket is
+    /// passed by systemd.
     pub socket_path: Option<PathBuf>,
     #[default("/dev/input/event0")]
     pub device: PathBuf,
-    pub btn_map: BTreeMap<KeyCode, KeyCode>,
+    pub btn_map: BTreeMap<KeyCode, ButtonMapping>,
     pub meta: MetaConfig,
-    #[default("tweakpoint")]
-    pub name: String,
-    #[default(1)]
-    pub vendor_id: u16,
-    #[default(1)]
-    pub product_id: u16,
-    #[default(1)]
-    pub product_version: u16,
-    #[default(BusType::BUS_USB)]
-    pub bus: BusType,
+    /// Copy name and ids of the physical device, so libinput quirks and hwdb
+    /// entries keep applying. Fields set below still take precedence.
+    pub inherit_identity: bool,
+    /// Appended to the inherited name.
+    pub name_suffix: Option<String>,
+    /// Defaults to `tweakpoint`, unless inherited.
+    pub name: Option<String>,
+    /// Defaults to 1, unless inherited.
+    pub vendor_id: Option<u16>,
+    /// Defaults to 1, unless inherited.
+    pub product_id: Option<u16>,
+    /// Defaults to 1, unless inherited.
+    pub product_version: Option<u16>,
+    /// Defaults to `BUS_USB`, unless inherited.
+    pub bus: Option<BusType>,
+    /// Input properties of the virtual pointer device, e.g. `POINTING_STICK`
+    /// for trackpoints.
+    #[default(vec![PropType::POINTER])]
+    pub properties: Vec<PropType>,
+    /// Name of the virtual keyboard emitting `KEY_*` codes, created once the
+    /// config or the device needs one.
+    #[default("tweakpoint keys")]
+    pub keys_name: String,
     pub axis_map: AxisMap,
     pub hi_res_enabled: bool,
     #[default(5)]
     pub min_gesture_movement: u32,
     #[default(true)]
     pub move_during_gesture: bool,
+    pub gesture: GestureConfig,
+    pub keyboard: Option<KeyboardConfig>,
+    /// Upper limit on autofire clicks per second, regardless of the configured
+    /// intervals.
+    pub autofire_max_rate: Option<f64>,
+    /// Pointer speed factors for `CycleSpeed`, `SpeedUp` and `SpeedDown`.
+    pub speed_levels: Vec<f64>,
+    /// Buttons that latch when held longer than the given time, and are
+    /// released by the next click.
+    pub click_lock: BTreeMap<KeyCode, humantime_serde::Serde<Duration>>,
+    pub persist: PersistConfig,
+    /// Named overlays over `btn_map` and `meta.chord`, activated by layer
+    /// actions.
+    pub layers: BTreeMap<String, Layer>,
+    /// Actions run by relative axis movement, by physical axis.
+    pub axis_triggers: HashMap<RelativeAxisCode, AxisTrigger>,
+}
+
+/// Runs an action every `threshold` counts of movement in a direction. Axis
+/// events in a direction with an action don't reach the system, and neither do
+/// their hi-res counterparts for wheels.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AxisTrigger {
+    /// Counts per action, `1` is a wheel detent or a tilt wheel click.
+    #[serde(default = "default_threshold")]
+    pub threshold: NonZeroU32,
+    pub positive: Option<Action>,
+    pub negative: Option<Action>,
+    /// Run the action for every threshold crossed while held, instead of once.
+    #[serde(default)]
+    pub repeat: bool,
+    /// Events further apart than this start a new hold.
+    #[serde(default = "default_hold_timeout", with = "humantime_serde")]
+    pub hold_timeout: Duration,
+}
+
+impl AxisTrigger {
+    pub fn action(&self, value: i32) -> Option<&Action> {
+        if value > 0 {
+            self.positive.as_ref()
+        } else {
+            self.negative.as_ref()
+        }
+    }
+}
+
+fn default_threshold() -> NonZeroU32 {
+    NonZeroU32::MIN
+}
+
+fn default_hold_timeout() -> Duration {
+    Duration::from_millis(200)
+}
+
+/// Ways to abort a gesture in progress. A cancelled gesture runs nothing on
+/// release.
+#[derive(Serialize, Deserialize, Debug, SmartDefault, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct GestureConfig {
+    /// Cancel gestures held longer than this.
+    #[serde(with = "humantime_serde")]
+    pub max_duration: Option<Duration>,
+    /// Cancel gestures with more strokes than this.
+    pub max_strokes: Option<usize>,
+    /// Cancel the gesture when another button is pressed. That press and its
+    /// release are swallowed.
+    pub cancel_on_button: bool,
+    /// Cancel the gesture when the pointer comes back within this many counts
+    /// of where the gesture started.
+    pub cancel_on_return: Option<u32>,
+    /// If no key matches, pick the closest key without `+` or `*`, at most
+    /// this many strokes off.
+    pub max_edit_distance: Option<usize>,
+    /// Strokes at least this many counts long are `:long`, shorter ones
+    /// `:short`.
+    pub long_stroke: Option<u32>,
+    /// Strokes at least this many counts per second are `:fast`, slower ones
+    /// `:slow`.
+    pub fast_stroke: Option<f64>,
+    /// Minimum score in `0.0..=1.0` for a `@shape` key to match.
+    #[default(0.85)]
+    pub min_shape_score: f64,
+    /// File with recorded shape templates, relative to the config file.
+    #[default("gesture-shapes.toml")]
+    pub shapes: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Layer {
+    pub btn_map: BTreeMap<KeyCode, ButtonMapping>,
+    pub chord: BTreeMap<KeyCode, Action>,
+}
+
+/// Runtime state to restore across restarts. Button locks are never
+/// persisted.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistConfig {
+    pub scroll: bool,
+    /// Pointer speed, including the speed level.
+    pub slow: bool,
+}
+
+impl PersistConfig {
+    pub fn any(&self) -> bool {
+        self.scroll || self.slow
+    }
+}
+
+/// What a physical button is mapped to.
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ButtonMapping {
+    /// Another button.
+    Key(KeyCode),
+    /// An action, pressed and released together with the button.
+    Action(Action),
+}
+
+// not untagged, which would hide the reason why neither variant matched
+impl<'de> Deserialize<'de> for ButtonMapping {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = toml::Value::deserialize(deserializer)?;
+        if !value.is_str() {
+            return Action::deserialize(value)
+                .map(Self::Action)
+                .map_err(D::Error::custom);
+        }
+        // plain strings are mostly key codes, so a typo there reports the
+        // unknown key rather than the unknown action
+        match KeyCode::deserialize(value.clone()) {
+            Ok(key) => Ok(Self::Key(key)),
+            Err(error) => Action::deserialize(value)
+                .map(Self::Action)
+                .map_err(|_| D::Error::custom(error)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KeyboardConfig {
+    pub device: PathBuf,
+    /// Grab the keyboard and pass unhandled keys through a separate virtual
+    /// keyboard. If not grabbed, handled keys also reach the system as usual.
+    #[serde(default = "default_true")]
+    pub grab: bool,
+    #[serde(default = "default_keyboard_name")]
+    pub name: String,
+    /// Keys that run an action: down on press, up on release.
+    #[serde(default)]
+    pub bind: BTreeMap<KeyCode, Action>,
+    /// Keys that are handled as if they were pointer buttons, so they can be
+    /// used as the meta key, chord triggers or in `btn_map`. Only used if
+    /// grabbed, as the system would otherwise see these keys twice.
+    #[serde(default)]
+    pub forward: BTreeSet<KeyCode>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_keyboard_name() -> String {
+    "tweakpoint keyboard".to_owned()
+}
+
+impl Config {
+    /// All actions in the config, not including nested ones.
+    pub fn actions(&self) -> impl Iterator<Item = &Action> {
+        let btn_maps =
+            std::iter::once(&self.btn_map).chain(self.layers.values().map(|x| &x.btn_map));
+        let chords =
+            std::iter::once(&self.meta.chord).chain(self.layers.values().map(|x| &x.chord));
+        [&self.meta.hold, &self.meta.r#move, &self.meta.click]
+            .into_iter()
+            .chain(chords.flat_map(|x| x.values()))
+            .chain(btn_maps.flat_map(|x| x.values()).filter_map(|x| match x {
+                ButtonMapping::Action(action) => Some(action),
+                ButtonMapping::Key(_) => None,
+            }))
+            .chain(self.keyboard.iter().flat_map(|x| x.bind.values()))
+            .chain(
+                self.axis_triggers
+                    .values()
+                    .flat_map(|x| x.positive.iter().chain(&x.negative)),
+            )
+    }
+
+    fn actions_mut(&mut self) -> impl Iterator<Item = &mut Action> {
+        fn action(mapping: &mut ButtonMapping) -> Option<&mut Action> {
+            match mapping {
+                ButtonMapping::Action(action) => Some(action),
+                ButtonMapping::Key(_) => None,
+            }
+        }
+        // sorted, so the order doesn't change across loads
+        let mut axis_triggers = self.axis_triggers.iter_mut().collect::<Vec<_>>();
+        axis_triggers.sort_by_key(|(axis, _)| axis.0);
+        [
+            &mut self.meta.hold,
+            &mut self.meta.r#move,
+            &mut self.meta.click,
+        ]
+        .into_iter()
+        .chain(self.meta.chord.values_mut())
+        .chain(self.btn_map.values_mut().filter_map(action))
+        .chain(self.layers.values_mut().flat_map(|x| {
+            x.chord
+                .values_mut()
+                .chain(x.btn_map.values_mut().filter_map(action))
+        }))
+        .chain(self.keyboard.iter_mut().flat_map(|x| x.bind.values_mut()))
+        .chain(
+            axis_triggers
+                .into_iter()
+                .flat_map(|(_, x)| x.positive.iter_mut().chain(&mut x.negative)),
+        )
+    }
+
+    /// Number the `If` and `Toggle` actions, whose state is kept by that id.
+    /// Ids follow the layout of the config, so they stay the same across
+    /// reloads as long as the actions don't move.
+    pub fn assign_action_ids(&mut self) {
+        let mut next = 0;
+        for action in self.actions_mut() {
+            action.walk_mut(&mut |action| {
+                let id = match action {
+                    Action::If(conditional) => &mut conditional.id,
+                    Action::Toggle(alternating) => &mut alternating.id,
+                    _ => return,
+                };
+                *id = Some(next);
+                next += 1;
+            });
+        }
+    }
+
+    /// Name and id of the virtual pointer device for the physical `device`.
+    pub fn identity(&self, device: &Device) -> (String, InputId) {
+        let (name, id) = if self.inherit_identity {
+            let name = device.name().unwrap_or("tweakpoint");
+            let suffix = self.name_suffix.as_deref().unwrap_or_default();
+            (format!("{name}{suffix}"), device.input_id())
+        } else {
+            (
+                "tweakpoint".to_owned(),
+                InputId::new(BusType::BUS_USB, 1, 1, 1),
+            )
+        };
+        let id = InputId::new(
+            self.bus.unwrap_or(id.bus_type()),
+            self.vendor_id.unwrap_or(id.vendor()),
+            self.product_id.unwrap_or(id.product()),
+            self.product_version.unwrap_or(id.version()),
+        );
+        (self.name.clone().unwrap_or(name), id)
+    }
+
+    /// Keys the virtual pointer device may emit on top of the physical
+    /// device's.
+    pub fn emitted_keys(&self) -> BTreeSet<KeyCode> {
+        let mut keys = BTreeSet::new();
+        for action in self.actions() {
+            action.walk(&mut |action| match action {
+                Action::Button(key) => {
+                    keys.insert(*key);
+                }
+                Action::ToggleLock(lock) => keys.extend(&lock.buttons),
+                Action::Autofire(autofire) => {
+                    keys.insert(autofire.button);
+                }
+                Action::Sequence(sequence) => {
+                    for step in &sequence.steps {
+                        if let SequenceStep::Down(key) | SequenceStep::Up(key) = step {
+                            keys.insert(*key);
+                        }
+                    }
+                }
+                _ => {}
+            });
+        }
+        let btn_maps =
+            std::iter::once(&self.btn_map).chain(self.layers.values().map(|x| &x.btn_map));
+        keys.extend(btn_maps.flat_map(|x| x.values()).filter_map(|x| match x {
+            ButtonMapping::Key(key) => Some(*key),
+            ButtonMapping::Action(_) => None,
+        }));
+        // forwarded keys are handled like pointer buttons, and emitted as-is
+        // unless mapped.
+        keys.extend(
+            self.keyboard
+                .iter()
+                .filter(|x| x.grab)
+                .flat_map(|x| &x.forward),
+        );
+        keys
+    }
+
+    /// Relative axes the virtual pointer device may emit on top of the
+    /// physical device's.
+    pub fn emitted_axes(&self) -> HashSet<RelativeAxisCode> {
+        let axis_map = &self.axis_map;
+        [&axis_map.regular, &axis_map.scroll]
+            .into_iter()
+            .chain(axis_map.held.values())
+            .chain(axis_map.chord.values())
+            .flat_map(|x| x.values())
+            .map(|x| x.axis)
+            .collect()
+    }
+
+    /// Check what can't be checked while parsing.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(rate) = self.autofire_max_rate
+            && rate > 0.0
+            && Duration::try_from_secs_f64(1.0 / rate).is_err()
+        {
+            anyhow::bail!("autofire_max_rate {rate} is too small");
+        }
+        if let Some(keyboard) = &self.keyboard
+            && !keyboard.grab
+            && !keyboard.forward.is_empty()
+        {
+            tracing::warn!("keyboard.forward is ignored unless keyboard.grab is set");
+        }
+        self.actions()
+            .try_for_each(|action| self.validate_action(action))
+    }
+
+    /// Check an action against the rest of the config.
+    pub fn validate_action(&self, action: &Action) -> anyhow::Result<()> {
+        let mut res = Ok(());
+        action.walk(&mut |action| match action {
+            Action::HoldLayer(name) | Action::ToggleLayer(name) | Action::OneShotLayer(name)
+                if !self.layers.contains_key(name) =>
+            {
+                res = Err(anyhow::anyhow!("Unknown layer {name:?}"));
+            }
+            Action::Gesture(gestures) => {
+                for (pattern, _, _) in gestures.0.iter() {
+                    if pattern.uses_length() && self.gesture.long_stroke.is_none() {
+                        res = Err(anyhow::anyhow!(
+                            "Gesture {:?} needs gesture.long_stroke",
+                            pattern.source()
+                        ));
+                    }
+                    if pattern.uses_speed() && self.gesture.fast_stroke.is_none() {
+                        res = Err(anyhow::anyhow!(
+                            "Gesture {:?} needs gesture.fast_stroke",
+                            pattern.source()
+                        ));
+                    }
+                }
+            }
+            Action::RecordShape(name) => {
+                if let Err(error) = GesturePattern::parse(&format!("@{name}")) {
+                    res = Err(error.into());
+                }
+            }
+            Action::Sequence(sequence) if sequence.repeat == 0 && !sequence.waits() => {
+                res = Err(anyhow::anyhow!("Endless sequence needs a non-zero delay"));
+            }
+            Action::Autofire(autofire) if autofire.interval.is_zero() => {
+                res = Err(anyhow::anyhow!(
+                    "Autofire of {:?} needs a non-zero interval",
+                    autofire.button
+                ));
+            }
+            _ => {}
+        });
+        res
+    }
+
+    pub fn socket_path(&self) -> Option<PathBuf> {
+        self.socket_path
+            .clone()
+            .or_else(tweakpoint::client::default_socket_path)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -41,18 +446,36 @@ pub struct Config {
 pub struct AxisMap {
     pub regular: HashMap<RelativeAxisCode, AxisMapDef>,
     pub scroll: HashMap<RelativeAxisCode, AxisMapDef>,
+    /// Tables that apply while a physical button is held, over the others.
+    /// The button is still pressed as usual; map it to `"None"` in `btn_map`
+    /// to only remap the axes.
+    pub held: BTreeMap<KeyCode, HashMap<RelativeAxisCode, AxisMapDef>>,
+    /// Tables that apply while a chord is held, by the chorded button. The
+    /// button needs a `meta.chord` entry, `"None"` will do.
+    pub chord: BTreeMap<KeyCode, HashMap<RelativeAxisCode, AxisMapDef>>,
 }
 
 impl AxisMap {
-    pub fn get(&self, axis: RelativeAxisCode, scroll_active: bool) -> AxisMapDef {
-        *scroll_active
-            .then(|| self.scroll.get(&axis))
-            .flatten()
+    /// Look up the axis in `scoped`, then in the scroll table if scroll mode
+    /// is active, then in the regular one.
+    pub fn get(
+        &self,
+        axis: RelativeAxisCode,
+        scroll_active: bool,
+        scoped: Option<&HashMap<RelativeAxisCode, AxisMapDef>>,
+    ) -> AxisMapDef {
+        *scoped
+            .and_then(|x| x.get(&axis))
+            .or_else(|| scroll_active.then(|| self.scroll.get(&axis)).flatten())
             .or_else(|| self.regular.get(&axis))
             .inspect(|new| {
                 tracing::trace!(old = ?axis, ?new, ?scroll_active, "Mapped axis");
             })
-            .unwrap_or(&AxisMapDef { axis, factor: 1.0 })
+            .unwrap_or(&AxisMapDef {
+                axis,
+                factor: 1.0,
+                acceleration: None,
+            })
     }
 }
 
@@ -62,6 +485,44 @@ pub struct AxisMapDef {
     pub axis: RelativeAxisCode,
     #[serde(default = "default_factor")]
     pub factor: f64,
+    /// Scale movement by how fast the axis moves, meant for mapping to
+    /// scroll axes.
+    pub acceleration: Option<ScrollAcceleration>,
+}
+
+/// Multiplier on top of `factor` that grows with the axis velocity, from
+/// `min` at `low_speed` and below to `max` at `high_speed` and above.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct ScrollAcceleration {
+    /// Counts per second.
+    #[serde(default)]
+    pub low_speed: f64,
+    /// Counts per second.
+    pub high_speed: f64,
+    #[serde(default = "default_factor")]
+    pub min: f64,
+    pub max: f64,
+    /// Shape of the curve in between; `1.0` is linear, larger values keep
+    /// slow scrolling fine for longer.
+    #[serde(default = "default_factor")]
+    pub exponent: f64,
+    /// Velocity is averaged over this window.
+    #[serde(default = "default_velocity_window", with = "humantime_serde")]
+    pub window: Duration,
+}
+
+impl ScrollAcceleration {
+    pub fn multiplier(&self, velocity: f64) -> f64 {
+        let t = ((velocity - self.low_speed) / (self.high_speed - self.low_speed)).clamp(0.0, 1.0);
+        // NaN if high_speed == low_speed
+        let t = if t.is_nan() { 1.0 } else { t };
+        self.min + (self.max - self.min) * t.powf(self.exponent)
+    }
+}
+
+fn default_velocity_window() -> Duration {
+    Duration::from_millis(100)
 }
 
 fn default_factor() -> f64 {
@@ -86,16 +547,359 @@ pub struct MetaConfig {
     pub click: Action,
 }
 
-pub type Gestures = HashMap<String, Action>;
+/// Gesture keys and their actions, sorted by [`GesturePattern::priority`],
+/// each with the action described for clients.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(
+    try_from = "BTreeMap<String, Action>",
+    into = "BTreeMap<String, Action>"
+)]
+pub struct Gestures(Arc<[(GesturePattern, Action, String)]>);
+
+impl TryFrom<BTreeMap<String, Action>> for Gestures {
+    type Error = PatternError;
+
+    fn try_from(value: BTreeMap<String, Action>) -> Result<Self, Self::Error> {
+        let mut gestures = value
+            .into_iter()
+            .map(|(key, action)| {
+                let description = action.describe();
+                Ok((GesturePattern::parse(&key)?, action, description))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        gestures.sort_by_cached_key(|(pattern, _, _)| pattern.priority());
+        Ok(Self(gestures.into()))
+    }
+}
+
+impl From<Gestures> for BTreeMap<String, Action> {
+    fn from(value: Gestures) -> Self {
+        value
+            .0
+            .iter()
+            .map(|(pattern, action, _)| (pattern.source().to_owned(), action.clone()))
+            .collect()
+    }
+}
+
+impl Gestures {
+    pub fn actions(&self) -> impl Iterator<Item = &Action> {
+        self.0.iter().map(|(_, action, _)| action)
+    }
+
+    /// Empty if the gestures are shared, which they aren't while loading.
+    fn actions_mut(&mut self) -> impl Iterator<Item = &mut Action> {
+        Arc::get_mut(&mut self.0)
+            .into_iter()
+            .flatten()
+            .map(|(_, action, _)| action)
+    }
+
+    /// Keys that may still match once the gesture is finished, given the
+    /// strokes so far, with their actions described.
+    pub fn reachable(&self, strokes: Vec<Stroke>) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .filter(move |(pattern, _, _)| pattern.reachable(&strokes))
+            .map(|(pattern, _, description)| (pattern.source(), description.as_str()))
+    }
+
+    /// Names of shapes used as keys.
+    pub fn shapes(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().filter_map(|(pattern, _, _)| pattern.shape())
+    }
+
+    /// Find the action for a finished gesture: an exact key, else the best
+    /// scoring shape, else the first matching pattern by priority, else the
+    /// closest exact key within `max_edit_distance` edits, else the `*`
+    /// fallback.
+    pub fn find(
+        &self,
+        strokes: &[Stroke],
+        shape_score: impl Fn(&str) -> f64,
+        config: &GestureConfig,
+    ) -> Option<(&str, &Action)> {
+        let matching = |exact: bool| {
+            self.0
+                .iter()
+                .filter(|(pattern, _, _)| !pattern.is_fallback() && pattern.is_exact() == exact)
+                .find(|(pattern, _, _)| pattern.matches(strokes))
+        };
+        let (pattern, action, _) = matching(true)
+            .or_else(|| {
+                self.0
+                    .iter()
+                    .filter_map(|x| {
+                        let score = shape_score(x.0.shape()?);
+                        tracing::trace!(shape = x.0.source(), score, "Shape score");
+                        (score >= config.min_shape_score).then_some((score, x))
+                    })
+                    .max_by(|a, b| a.0.total_cmp(&b.0))
+                    .map(|(_, x)| x)
+            })
+            .or_else(|| matching(false))
+            .or_else(|| {
+                let max_distance = config.max_edit_distance?;
+                self.0
+                    .iter()
+                    .filter_map(|x| Some((x.0.distance(strokes)?, x)))
+                    .filter(|(distance, _)| *distance <= max_distance)
+                    .min_by_key(|(distance, _)| *distance)
+                    .map(|(_, x)| x)
+            })
+            .or_else(|| self.0.iter().find(|(pattern, _, _)| pattern.is_fallback()))?;
+        Some((pattern.source(), action))
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Action {
     None,
     ToggleScroll,
+    HoldScroll,
     ToggleSlow(f64),
-    ToggleLock(BTreeSet<KeyCode>),
+    /// Switch to the next of `speed_levels`, wrapping around.
+    CycleSpeed,
+    /// Switch to the closest of `speed_levels` faster than the current speed.
+    SpeedUp,
+    /// Switch to the closest of `speed_levels` slower than the current speed.
+    SpeedDown,
+    /// Switch back to the default speed.
+    ResetSpeed,
+    ToggleLock(LockConfig),
     Button(KeyCode),
     Gesture(Gestures),
+    ContinuousGesture(Box<ContinuousGesture>),
+    Sequence(Sequence),
+    Autofire(Autofire),
+    /// Run one of two actions depending on a condition, evaluated on press.
+    If(Box<Conditional>),
+    /// Alternate between two actions on successive presses.
+    Toggle(Box<Alternating>),
+    SetScroll(bool),
+    /// Set the pointer speed factor; `1.0` resets to the default speed. A
+    /// plain number rather than an optional one, as TOML has no null to write
+    /// "default" with.
+    SetSlow(f64),
+    /// Activate a layer while held.
+    HoldLayer(String),
+    ToggleLayer(String),
+    /// Activate a layer for the next button press.
+    OneShotLayer(String),
+    /// Record the movement while held as the shape template with this name,
+    /// to be used as a `@name` gesture key.
+    RecordShape(String),
+}
+
+/// Fires actions repeatedly while held: once per `step` counts of movement in
+/// a direction. Only the dominant axis of each movement counts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ContinuousGesture {
+    #[serde(rename = "U")]
+    pub up: Option<GestureStep>,
+    #[serde(rename = "D")]
+    pub down: Option<GestureStep>,
+    #[serde(rename = "L")]
+    pub left: Option<GestureStep>,
+    #[serde(rename = "R")]
+    pub right: Option<GestureStep>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GestureStep {
+    /// Movement in counts per action.
+    pub step: NonZeroU32,
+    pub action: Action,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Conditional {
+    pub cond: Condition,
+    pub then: Action,
+    #[serde(rename = "else", default = "default_action")]
+    pub r#else: Action,
+    /// See [`Config::assign_action_ids`].
+    #[serde(skip)]
+    pub id: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Alternating {
+    pub on: Action,
+    pub off: Action,
+    /// See [`Config::assign_action_ids`].
+    #[serde(skip)]
+    pub id: Option<usize>,
+}
+
+fn default_action() -> Action {
+    Action::None
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Condition {
+    ScrollActive,
+    /// Pointer speed differs from the default.
+    SlowActive,
+    /// Button lock is toggled on.
+    LockEngaged,
+    /// Some button is held by the button lock or ClickLock.
+    ButtonLocked,
+    GestureActive,
+    SequenceRunning,
+    Not(Box<Condition>),
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+}
+
+impl Condition {
+    pub fn eval(&self, state: &State) -> bool {
+        match self {
+            Condition::ScrollActive => state.scroll.active,
+            Condition::SlowActive => state.slow.is_some_and(|x| x != 1.0),
+            Condition::LockEngaged => state.lock.is_engaged(),
+            Condition::ButtonLocked => state
+                .lock
+                .state_vec()
+                .chain(state.click_lock.state_vec())
+                .any(|(_, step)| matches!(step, LockStep::Locked | LockStep::WillRelease)),
+            Condition::GestureActive => state.gesture_active(),
+            Condition::SequenceRunning => state.sequence.is_running(),
+            Condition::Not(cond) => !cond.eval(state),
+            Condition::All(conds) => conds.iter().all(|x| x.eval(state)),
+            Condition::Any(conds) => conds.iter().any(|x| x.eval(state)),
+        }
+    }
+}
+
+/// Repeatedly click a button while the action is held.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Autofire {
+    pub button: KeyCode,
+    /// Time between consecutive clicks.
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// Time between the first click and the start of repetition. The first
+    /// click is immediate.
+    #[serde(default, with = "humantime_serde")]
+    pub delay: Duration,
+}
+
+/// Button lock. Can be given as just a list of buttons.
+#[derive(Serialize, Debug, Clone)]
+pub struct LockConfig {
+    pub buttons: BTreeSet<KeyCode>,
+    /// Release locked buttons after this long without any input.
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Option<Duration>,
+    /// Release locked buttons when any other button is pressed.
+    pub release_on_other_button: bool,
+    /// Release locked buttons when the pointer stops moving for this long.
+    #[serde(with = "humantime_serde")]
+    pub release_on_stop: Option<Duration>,
+}
+
+// not untagged, which would hide the reason why neither form matched
+impl<'de> Deserialize<'de> for LockConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = toml::Value::deserialize(deserializer)?;
+        if value.is_array() {
+            return BTreeSet::deserialize(value)
+                .map(|buttons| Self {
+                    buttons,
+                    idle_timeout: None,
+                    release_on_other_button: false,
+                    release_on_stop: None,
+                })
+                .map_err(D::Error::custom);
+        }
+        LockConfigTable::deserialize(value)
+            .map(Self::from)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LockConfigTable {
+    buttons: BTreeSet<KeyCode>,
+    #[serde(default, with = "humantime_serde")]
+    idle_timeout: Option<Duration>,
+    #[serde(default)]
+    release_on_other_button: bool,
+    #[serde(default, with = "humantime_serde")]
+    release_on_stop: Option<Duration>,
+}
+
+impl From<LockConfigTable> for LockConfig {
+    fn from(value: LockConfigTable) -> Self {
+        let LockConfigTable {
+            buttons,
+            idle_timeout,
+            release_on_other_button,
+            release_on_stop,
+        } = value;
+        Self {
+            buttons,
+            idle_timeout,
+            release_on_other_button,
+            release_on_stop,
+        }
+    }
+}
+
+/// Steps run one after another, with optional delays in between. Fires on
+/// press and runs to completion regardless of release.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Sequence {
+    pub steps: Vec<SequenceStep>,
+    /// How many times to run the steps. `0` repeats until cancelled.
+    #[serde(default = "default_repeat")]
+    pub repeat: u32,
+    /// What to do if triggered while a sequence is already running.
+    #[serde(default)]
+    pub on_busy: SequenceConflict,
+}
+
+fn default_repeat() -> u32 {
+    1
+}
+
+impl Sequence {
+    /// Whether a run takes any time, so repeating it doesn't spin.
+    pub fn waits(&self) -> bool {
+        self.steps
+            .iter()
+            .any(|x| matches!(x, SequenceStep::Delay(delay) if !delay.is_zero()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SequenceStep {
+    /// Press and release an action.
+    Action(Action),
+    Down(KeyCode),
+    Up(KeyCode),
+    Delay(#[serde(with = "humantime_serde")] Duration),
+}
+
+/// Whenever a running sequence is cancelled, keys it pressed but didn't
+/// release yet are released.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum SequenceConflict {
+    /// Cancel the running sequence and start this one.
+    #[default]
+    Restart,
+    /// Let the running sequence finish, don't start this one.
+    Ignore,
+    /// Cancel the running sequence, don't start this one.
+    Stop,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -106,6 +910,89 @@ pub enum Direction {
 }
 
 impl Action {
+    /// The action written the same way as in the config file.
+    pub fn describe(&self) -> String {
+        let mut out = String::new();
+        match self.serialize(toml::ser::ValueSerializer::new(&mut out)) {
+            Ok(()) => out,
+            Err(_) => format!("{self:?}"),
+        }
+    }
+
+    /// Call `f` on this action and all nested ones.
+    pub fn walk(&self, f: &mut impl FnMut(&Action)) {
+        f(self);
+        match self {
+            Action::Gesture(gestures) => gestures.actions().for_each(|x| x.walk(f)),
+            Action::ContinuousGesture(gesture) => {
+                for step in [&gesture.up, &gesture.down, &gesture.left, &gesture.right]
+                    .into_iter()
+                    .flatten()
+                {
+                    step.action.walk(f);
+                }
+            }
+            Action::Sequence(sequence) => {
+                for step in &sequence.steps {
+                    if let SequenceStep::Action(action) = step {
+                        action.walk(f);
+                    }
+                }
+            }
+            Action::If(conditional) => {
+                conditional.then.walk(f);
+                conditional.r#else.walk(f);
+            }
+            Action::Toggle(alternating) => {
+                alternating.on.walk(f);
+                alternating.off.walk(f);
+            }
+            _ => {}
+        }
+    }
+
+    fn walk_mut(&mut self, f: &mut impl FnMut(&mut Action)) {
+        f(self);
+        match self {
+            Action::Gesture(gestures) => gestures.actions_mut().for_each(|x| x.walk_mut(f)),
+            Action::ContinuousGesture(gesture) => {
+                for step in [
+                    &mut gesture.up,
+                    &mut gesture.down,
+                    &mut gesture.left,
+                    &mut gesture.right,
+                ]
+                .into_iter()
+                .flatten()
+                {
+                    step.action.walk_mut(f);
+                }
+            }
+            Action::Sequence(sequence) => {
+                for step in &mut sequence.steps {
+                    if let SequenceStep::Action(action) = step {
+                        action.walk_mut(f);
+                    }
+                }
+            }
+            Action::If(conditional) => {
+                conditional.then.walk_mut(f);
+                conditional.r#else.walk_mut(f);
+            }
+            Action::Toggle(alternating) => {
+                alternating.on.walk_mut(f);
+                alternating.off.walk_mut(f);
+            }
+            _ => {}
+        }
+    }
+
+    /// Key for state kept across presses. Actions from the socket have no
+    /// id, and are kept by their description.
+    fn state_key(&self, id: Option<usize>) -> ActionKey {
+        id.map_or_else(|| ActionKey::Description(self.describe()), ActionKey::Id)
+    }
+
     pub fn run(
         &self,
         state: &mut State,
@@ -117,31 +1004,244 @@ impl Action {
             Action::ToggleScroll if matches!(dir, Direction::Down) => {
                 tracing::debug!("ToggleScroll action executing");
                 state.scroll.toggle();
-                None.left().left()
+                None.left().left().left()
+            }
+            Action::HoldScroll => {
+                tracing::debug!(?dir, "HoldScroll action executing");
+                match dir {
+                    Direction::Down => state.scroll.hold(),
+                    Direction::Up => state.scroll.release(),
+                }
+                None.left().left().left()
             }
             Action::ToggleSlow(factor) if matches!(dir, Direction::Down) => {
                 tracing::debug!("ToggleScroll action executing");
                 state.slow = state.slow.is_none().then_some(*factor);
-                None.left().left()
+                state.speed.level = None;
+                None.left().left().left()
+            }
+            Action::CycleSpeed if matches!(dir, Direction::Down) => {
+                tracing::debug!("CycleSpeed action executing");
+                state.cycle_speed();
+                None.left().left().left()
+            }
+            Action::SpeedUp if matches!(dir, Direction::Down) => {
+                tracing::debug!("SpeedUp action executing");
+                state.step_speed(true);
+                None.left().left().left()
+            }
+            Action::SpeedDown if matches!(dir, Direction::Down) => {
+                tracing::debug!("SpeedDown action executing");
+                state.step_speed(false);
+                None.left().left().left()
+            }
+            Action::ResetSpeed if matches!(dir, Direction::Down) => {
+                tracing::debug!("ResetSpeed action executing");
+                state.set_speed_level(None);
+                None.left().left().left()
             }
             Action::Button(key_code) => {
                 tracing::debug!(?key_code, ?dir, "Button action executing");
                 Some(InputEvent::new(EventType::KEY.0, key_code.0, dir as i32))
                     .left()
                     .left()
+                    .left()
             }
-            Action::ToggleLock(lock_btns) if matches!(dir, Direction::Down) => {
-                tracing::debug!(?lock_btns, "ToggleLock action executing");
-                state.lock.toggle(lock_btns).right().right()
+            Action::ToggleLock(lock) if matches!(dir, Direction::Down) => {
+                tracing::debug!(?lock, "ToggleLock action executing");
+                state.lock.toggle(lock).right().right().left()
             }
             Action::Gesture(config) => match dir {
-                Direction::Down => state.start_gesture().right().left(),
-                Direction::Up => state.end_gesture(config).left().right(),
+                Direction::Down => state.start_gesture(Some(config)).right().left().left(),
+                Direction::Up => state.end_gesture(config).left().right().left(),
+            },
+            Action::RecordShape(name) => match dir {
+                Direction::Down => state.start_gesture(None).right().left().left(),
+                Direction::Up => {
+                    state.record_shape(name);
+                    None.left().left().left()
+                }
             },
+            Action::ContinuousGesture(gesture) => {
+                tracing::debug!(?dir, "ContinuousGesture action executing");
+                state.continuous_gesture = match dir {
+                    Direction::Down => Some(ContinuousGestureState::new(gesture.clone())),
+                    Direction::Up => None,
+                };
+                None.left().left().left()
+            }
+            Action::Sequence(sequence) if matches!(dir, Direction::Down) => {
+                tracing::debug!(?sequence, "Sequence action executing");
+                state.start_sequence(sequence).right()
+            }
+            Action::Autofire(autofire) => match dir {
+                Direction::Down => {
+                    tracing::debug!(?autofire, "Autofire action starting");
+                    state.autofire.start(autofire).right()
+                }
+                Direction::Up => {
+                    tracing::debug!(?autofire, "Autofire action stopping");
+                    state.autofire.stop(autofire.button).right()
+                }
+            },
+            Action::If(conditional) => {
+                // release goes to the branch taken on press.
+                let key = self.state_key(conditional.id);
+                let then = match dir {
+                    Direction::Down => {
+                        let then = conditional.cond.eval(state);
+                        state.conditionals.insert(key, then);
+                        then
+                    }
+                    Direction::Up => state
+                        .conditionals
+                        .remove(&key)
+                        .unwrap_or_else(|| conditional.cond.eval(state)),
+                };
+                tracing::debug!(cond = ?conditional.cond, then, "If action executing");
+                let action = if then {
+                    &conditional.then
+                } else {
+                    &conditional.r#else
+                };
+                action
+                    .run(state, dir, "If branch")
+                    .into_iter()
+                    .collect::<Vec<_>>()
+                    .right()
+            }
+            Action::Toggle(alternating) => {
+                let toggle = state
+                    .toggles
+                    .entry(self.state_key(alternating.id))
+                    .or_default();
+                let on = match dir {
+                    Direction::Down => {
+                        toggle.is_on = !toggle.is_on;
+                        toggle.pressed_on = toggle.is_on;
+                        toggle.is_on
+                    }
+                    Direction::Up => toggle.pressed_on,
+                };
+                tracing::debug!(on, "Toggle action executing");
+                let action = if on {
+                    &alternating.on
+                } else {
+                    &alternating.off
+                };
+                action
+                    .run(state, dir, "Toggle branch")
+                    .into_iter()
+                    .collect::<Vec<_>>()
+                    .right()
+            }
+            Action::SetScroll(active) if matches!(dir, Direction::Down) => {
+                tracing::debug!(active, "SetScroll action executing");
+                state.scroll.set(*active);
+                None.left().left().left()
+            }
+            Action::SetSlow(factor) if matches!(dir, Direction::Down) => {
+                tracing::debug!(factor, "SetSlow action executing");
+                state.slow = (*factor != 1.0).then_some(*factor);
+                state.speed.level = None;
+                None.left().left().left()
+            }
+            Action::HoldLayer(name) => {
+                match dir {
+                    Direction::Down => state.layers.activate(name, true),
+                    Direction::Up => state.layers.deactivate(name),
+                }
+                None.left().left().left()
+            }
+            Action::ToggleLayer(name) if matches!(dir, Direction::Down) => {
+                state.layers.toggle(name);
+                None.left().left().left()
+            }
+            Action::OneShotLayer(name) if matches!(dir, Direction::Down) => {
+                state.layers.set_one_shot(name);
+                None.left().left().left()
+            }
             Action::ToggleScroll
+            | Action::ToggleLayer(_)
+            | Action::OneShotLayer(_)
             | Action::ToggleSlow { .. }
+            | Action::SetScroll(_)
+            | Action::SetSlow(_)
+            | Action::CycleSpeed
+            | Action::SpeedUp
+            | Action::SpeedDown
+            | Action::ResetSpeed
             | Action::ToggleLock(_)
-            | Action::None => None.left().left(),
+            | Action::Sequence(_)
+            | Action::None => None.left().left().left(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(config: &Config, key: KeyCode, state: &mut State) {
+        let Some(ButtonMapping::Action(action)) = config.btn_map.get(&key) else {
+            panic!("{key:?} not mapped to an action");
+        };
+        action
+            .run(state, Direction::Down, "test")
+            .into_iter()
+            .count();
+        action.run(state, Direction::Up, "test").into_iter().count();
+    }
+
+    #[test]
+    fn identical_toggles_keep_separate_state() {
+        let mut config: Config = toml::from_str(
+            r#"
+            btn_map.BTN_SIDE = { Toggle = { on = { SetScroll = true }, off = { SetScroll = false } } }
+            btn_map.BTN_EXTRA = { Toggle = { on = { SetScroll = true }, off = { SetScroll = false } } }
+            "#,
+        )
+        .unwrap();
+        config.assign_action_ids();
+        let mut state = State::default();
+        press(&config, KeyCode::BTN_SIDE, &mut state);
+        assert!(state.scroll.active);
+        // the first press of the other button turns it on too, rather than
+        // continuing where the first one left off
+        press(&config, KeyCode::BTN_EXTRA, &mut state);
+        assert!(state.scroll.active);
+        press(&config, KeyCode::BTN_SIDE, &mut state);
+        assert!(!state.scroll.active);
+    }
+
+    #[test]
+    fn action_ids_are_unique_and_stable() {
+        let source = r#"
+            btn_map.BTN_SIDE = { If = { cond = "ScrollActive", then = { Toggle = { on = "None", off = "None" } } } }
+            meta.chord.BTN_LEFT = { Toggle = { on = "None", off = "None" } }
+            layers.nav.btn_map.BTN_EXTRA = { Toggle = { on = "None", off = "None" } }
+            axis_triggers.REL_WHEEL = { positive = { Toggle = { on = "None", off = "None" } } }
+            axis_triggers.REL_HWHEEL = { negative = { Toggle = { on = "None", off = "None" } } }
+        "#;
+        let ids = || {
+            let mut config: Config = toml::from_str(source).unwrap();
+            config.assign_action_ids();
+            let mut ids = vec![];
+            for action in config.actions_mut() {
+                action.walk_mut(&mut |action| match action {
+                    Action::If(x) => ids.push(x.id),
+                    Action::Toggle(x) => ids.push(x.id),
+                    _ => {}
+                });
+            }
+            ids
+        };
+        let first = ids();
+        assert_eq!(first.len(), 6);
+        let unique = first.iter().map(|x| x.unwrap()).collect::<HashSet<_>>();
+        assert_eq!(unique.len(), 6);
+        for _ in 0..4 {
+            assert_eq!(ids(), first);
         }
     }
 }
@@ -0,0 +1,910 @@
+//! Gesture key patterns and shape recognition.
+//!
+//! A key is either a sequence of `U`, `D`, `L`, `R` strokes, or `@name`,
+//! matching the movement trail against the shape template `name`. A stroke may
+//! be qualified with `:short` or `:long`, and `:slow` or `:fast`, e.g.
+//! `R:long:fast`; followed by `+` it matches one or more such strokes. `*`
+//! matches any strokes, including none.
+//!
+//! Movement in the same direction continues the current stroke, so
+//! consecutive strokes always differ in direction. `R+` thus matches the same
+//! as `R`, and keys with adjacent strokes in the same direction, like `RR` or
+//! `R+R`, are rejected as they could never match.
+//!
+//! Shapes are matched in the style of the $1 recognizer: the trail is
+//! resampled to a fixed number of points, centered, scaled and compared
+//! point-by-point to the template at the best angle within a small range.
+
+use std::{cmp::Reverse, collections::BTreeMap, f64::consts::PI, fmt, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tweakpoint::protocol::GestureDir;
+
+use crate::persist::write_file;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Length {
+    Short,
+    Long,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    Slow,
+    Fast,
+}
+
+/// A stroke of a gesture. Classes are `None` when not known, e.g. while the
+/// stroke is still in progress, and then match any qualifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stroke {
+    pub dir: GestureDir,
+    pub length: Option<Length>,
+    pub speed: Option<Speed>,
+}
+
+impl Stroke {
+    fn matches(&self, stroke: &Stroke) -> bool {
+        self.dir == stroke.dir
+            && self
+                .length
+                .is_none_or(|x| stroke.length.is_none_or(|y| x == y))
+            && self
+                .speed
+                .is_none_or(|x| stroke.speed.is_none_or(|y| x == y))
+    }
+
+    fn qualifiers(&self) -> usize {
+        usize::from(self.length.is_some()) + usize::from(self.speed.is_some())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    One(Stroke),
+    OneOrMore(Stroke),
+    Any,
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    Strokes(Vec<Token>),
+    Shape(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct GesturePattern {
+    source: String,
+    kind: Kind,
+}
+
+#[derive(Debug)]
+pub struct PatternError {
+    pattern: String,
+    reason: &'static str,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid gesture {:?}: {}", self.pattern, self.reason)
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+impl GesturePattern {
+    pub fn parse(source: &str) -> Result<Self, PatternError> {
+        let error = |reason| PatternError {
+            pattern: source.to_owned(),
+            reason,
+        };
+        if let Some(name) = source.strip_prefix('@') {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                return Err(error("shape names may only contain a-z, 0-9, `_` and `-`"));
+            }
+            return Ok(Self {
+                source: source.to_owned(),
+                kind: Kind::Shape(name.to_owned()),
+            });
+        }
+        let mut tokens = vec![];
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            let dir = |dir| {
+                Token::One(Stroke {
+                    dir,
+                    length: None,
+                    speed: None,
+                })
+            };
+            let token = match c {
+                'U' => dir(GestureDir::U),
+                'D' => dir(GestureDir::D),
+                'L' => dir(GestureDir::L),
+                'R' => dir(GestureDir::R),
+                ':' => {
+                    let Some(Token::One(mut stroke)) = tokens.pop() else {
+                        return Err(error("`:` must follow a direction"));
+                    };
+                    let mut word = String::new();
+                    while let Some(c) = chars.next_if(char::is_ascii_lowercase) {
+                        word.push(c);
+                    }
+                    match word.as_str() {
+                        "short" if stroke.length.is_none() => stroke.length = Some(Length::Short),
+                        "long" if stroke.length.is_none() => stroke.length = Some(Length::Long),
+                        "slow" if stroke.speed.is_none() => stroke.speed = Some(Speed::Slow),
+                        "fast" if stroke.speed.is_none() => stroke.speed = Some(Speed::Fast),
+                        "short" | "long" | "slow" | "fast" => {
+                            return Err(error("conflicting stroke qualifiers"));
+                        }
+                        _ => {
+                            return Err(error(
+                                "expected one of `:short`, `:long`, `:slow` or `:fast`",
+                            ));
+                        }
+                    }
+                    Token::One(stroke)
+                }
+                '*' if tokens.last() == Some(&Token::Any) => {
+                    return Err(error("repeated `*`"));
+                }
+                '*' => Token::Any,
+                '+' => match tokens.pop() {
+                    Some(Token::One(stroke)) => Token::OneOrMore(stroke),
+                    _ => return Err(error("`+` must follow a direction")),
+                },
+                _ => {
+                    return Err(error(
+                        "expected one of U, D, L, R, `:`, `+`, `*` or a leading `@`",
+                    ));
+                }
+            };
+            tokens.push(token);
+        }
+        let dir = |token: &Token| match token {
+            Token::One(stroke) | Token::OneOrMore(stroke) => Some(stroke.dir),
+            Token::Any => None,
+        };
+        if tokens
+            .windows(2)
+            .any(|w| dir(&w[0]).is_some() && dir(&w[0]) == dir(&w[1]))
+        {
+            return Err(error(
+                "adjacent strokes in the same direction merge into one, so never match",
+            ));
+        }
+        Ok(Self {
+            source: source.to_owned(),
+            kind: Kind::Strokes(tokens),
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    fn tokens(&self) -> &[Token] {
+        match &self.kind {
+            Kind::Strokes(tokens) => tokens,
+            Kind::Shape(_) => &[],
+        }
+    }
+
+    fn strokes(&self) -> impl Iterator<Item = &Stroke> {
+        self.tokens().iter().filter_map(|x| match x {
+            Token::One(stroke) | Token::OneOrMore(stroke) => Some(stroke),
+            Token::Any => None,
+        })
+    }
+
+    /// Whether the pattern uses `:short` or `:long`.
+    pub fn uses_length(&self) -> bool {
+        self.strokes().any(|x| x.length.is_some())
+    }
+
+    /// Whether the pattern uses `:slow` or `:fast`.
+    pub fn uses_speed(&self) -> bool {
+        self.strokes().any(|x| x.speed.is_some())
+    }
+
+    /// Whether the pattern matches a fixed number of strokes.
+    pub fn is_exact(&self) -> bool {
+        matches!(&self.kind, Kind::Strokes(tokens) if tokens.iter().all(|x| matches!(x, Token::One(_))))
+    }
+
+    /// The pattern is just `*`.
+    pub fn is_fallback(&self) -> bool {
+        self.tokens() == [Token::Any]
+    }
+
+    pub fn shape(&self) -> Option<&str> {
+        match &self.kind {
+            Kind::Shape(name) => Some(name),
+            Kind::Strokes(_) => None,
+        }
+    }
+
+    /// Sort key, most specific first: exact keys, then shapes, then
+    /// repetitions, then prefix/suffix patterns; within those by number of
+    /// strokes and qualifiers; then the `*` fallback.
+    pub fn priority(&self) -> impl Ord + use<> {
+        let class = if self.is_exact() {
+            0
+        } else if self.shape().is_some() {
+            1
+        } else if !self.tokens().contains(&Token::Any) {
+            2
+        } else if !self.is_fallback() {
+            3
+        } else {
+            4
+        };
+        let specificity = self.strokes().map(|x| 1 + x.qualifiers()).sum::<usize>();
+        (class, Reverse(specificity), self.source.clone())
+    }
+
+    pub fn matches(&self, strokes: &[Stroke]) -> bool {
+        match &self.kind {
+            Kind::Strokes(tokens) => matches(tokens, strokes),
+            Kind::Shape(_) => false,
+        }
+    }
+
+    /// Whether the strokes so far can still be completed to a match. Shapes
+    /// always can.
+    pub fn reachable(&self, strokes: &[Stroke]) -> bool {
+        match &self.kind {
+            Kind::Strokes(tokens) => reachable(tokens, strokes),
+            Kind::Shape(_) => true,
+        }
+    }
+
+    /// Edit distance to `strokes`, for exact patterns only.
+    pub fn distance(&self, strokes: &[Stroke]) -> Option<usize> {
+        self.is_exact()
+            .then(|| edit_distance(&self.strokes().copied().collect::<Vec<_>>(), strokes))
+    }
+}
+
+fn matches(tokens: &[Token], strokes: &[Stroke]) -> bool {
+    match tokens.split_first() {
+        None => strokes.is_empty(),
+        Some((Token::One(stroke), rest)) => {
+            strokes.first().is_some_and(|x| stroke.matches(x)) && matches(rest, &strokes[1..])
+        }
+        Some((Token::OneOrMore(stroke), rest)) => {
+            let run = strokes.iter().take_while(|x| stroke.matches(x)).count();
+            (1..=run).any(|n| matches(rest, &strokes[n..]))
+        }
+        Some((Token::Any, rest)) => (0..=strokes.len()).any(|n| matches(rest, &strokes[n..])),
+    }
+}
+
+fn reachable(tokens: &[Token], strokes: &[Stroke]) -> bool {
+    let Some(first) = strokes.first() else {
+        return true;
+    };
+    match tokens.split_first() {
+        None => false,
+        Some((Token::One(stroke), rest)) => stroke.matches(first) && reachable(rest, &strokes[1..]),
+        Some((Token::OneOrMore(stroke), rest)) => {
+            stroke.matches(first)
+                && (reachable(rest, &strokes[1..]) || reachable(tokens, &strokes[1..]))
+        }
+        Some((Token::Any, _)) => true,
+    }
+}
+
+fn edit_distance(a: &[Stroke], b: &[Stroke]) -> usize {
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, x) in a.iter().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, y) in b.iter().enumerate() {
+            let substitute = prev[j] + usize::from(!x.matches(y));
+            cur.push(substitute.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+pub type Point = (f64, f64);
+
+const RESAMPLE_POINTS: usize = 32;
+/// How far off the template's angle a shape may be drawn.
+const ANGLE_RANGE: f64 = PI / 4.0;
+const ANGLE_PRECISION: f64 = PI / 90.0;
+
+/// Shape template as stored in the shapes file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ShapeTemplate {
+    /// Match the shape at any angle, e.g. for circles that may start anywhere.
+    #[serde(default)]
+    pub rotation_invariant: bool,
+    pub points: Vec<Point>,
+}
+
+/// Template prepared for matching.
+struct Shape {
+    rotation_invariant: bool,
+    points: Vec<Point>,
+}
+
+impl Shape {
+    fn new(template: &ShapeTemplate) -> Option<Self> {
+        Some(Self {
+            rotation_invariant: template.rotation_invariant,
+            points: normalize(&template.points, template.rotation_invariant)?,
+        })
+    }
+
+    /// Score in `0.0..=1.0` of how well the trail matches the shape.
+    fn score(&self, trail: &[Point]) -> f64 {
+        let Some(candidate) = normalize(trail, self.rotation_invariant) else {
+            return 0.0;
+        };
+        let distance = distance_at_best_angle(&candidate, &self.points);
+        // shapes fit the unit square, so being off by half of it on average
+        // is as bad as it gets
+        (1.0 - distance / 0.5).max(0.0)
+    }
+}
+
+/// Built-in and user-recorded shape templates. User templates are kept in a
+/// TOML file alongside the config, and override built-ins of the same name.
+pub struct Shapes {
+    path: Option<PathBuf>,
+    user: BTreeMap<String, ShapeTemplate>,
+    shapes: BTreeMap<String, Shape>,
+}
+
+impl Default for Shapes {
+    fn default() -> Self {
+        let mut shapes = Self {
+            path: None,
+            user: BTreeMap::new(),
+            shapes: BTreeMap::new(),
+        };
+        shapes.prepare();
+        shapes
+    }
+}
+
+impl Shapes {
+    pub fn load(path: PathBuf) -> Self {
+        let user = match std::fs::read_to_string(&path) {
+            Ok(data) => toml::from_str(&data).unwrap_or_else(|error| {
+                tracing::error!(%error, path = %path.display(), "Invalid shapes file, ignoring");
+                BTreeMap::new()
+            }),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => {
+                tracing::error!(%error, path = %path.display(), "Error reading shapes file");
+                BTreeMap::new()
+            }
+        };
+        let mut shapes = Self {
+            path: Some(path),
+            user,
+            shapes: BTreeMap::new(),
+        };
+        shapes.prepare();
+        shapes
+    }
+
+    fn prepare(&mut self) {
+        self.shapes = builtin_templates()
+            .into_iter()
+            .map(|(name, template)| (name.to_owned(), template))
+            .chain(self.user.iter().map(|(name, x)| (name.clone(), x.clone())))
+            .filter_map(|(name, template)| {
+                let shape = Shape::new(&template);
+                if shape.is_none() {
+                    tracing::warn!(%name, "Degenerate shape template, ignoring");
+                }
+                Some((name, shape?))
+            })
+            .collect();
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.shapes.contains_key(name)
+    }
+
+    /// Score in `0.0..=1.0` of how well the trail matches the named shape.
+    pub fn score(&self, name: &str, trail: &[Point]) -> f64 {
+        self.shapes
+            .get(name)
+            .map_or(0.0, |shape| shape.score(trail))
+    }
+
+    /// Store the trail as a user template and write the shapes file.
+    pub fn record(&mut self, name: &str, trail: &[Point]) {
+        let Some(points) = normalize(trail, false) else {
+            tracing::warn!(name, "Recorded shape is too small, ignoring");
+            return;
+        };
+        tracing::info!(name, "Recorded shape");
+        self.user.insert(
+            name.to_owned(),
+            ShapeTemplate {
+                rotation_invariant: false,
+                points,
+            },
+        );
+        self.prepare();
+        let Some(path) = &self.path else {
+            return;
+        };
+        let res = toml::to_string(&self.user)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(write_file(path, data)?));
+        if let Err(error) = res {
+            tracing::error!(%error, path = %path.display(), "Error writing shapes file");
+        }
+    }
+}
+
+fn builtin_templates() -> [(&'static str, ShapeTemplate); 4] {
+    // screen coordinates, i.e. Y grows downwards
+    let circle = |sign: f64| ShapeTemplate {
+        rotation_invariant: true,
+        points: (0..=64)
+            .map(|i| {
+                let t = 2.0 * PI * f64::from(i) / 64.0;
+                (sign * t.sin(), -t.cos())
+            })
+            .collect(),
+    };
+    let polyline = |points: &[Point]| ShapeTemplate {
+        rotation_invariant: false,
+        points: points.to_vec(),
+    };
+    [
+        ("circle_cw", circle(1.0)),
+        ("circle_ccw", circle(-1.0)),
+        ("check", polyline(&[(0.0, 0.0), (1.0, 1.0), (3.0, -2.0)])),
+        (
+            "zigzag",
+            polyline(&[
+                (0.0, 0.0),
+                (1.0, 2.0),
+                (2.0, 0.0),
+                (3.0, 2.0),
+                (4.0, 0.0),
+                (5.0, 2.0),
+            ]),
+        ),
+    ]
+}
+
+/// Resample, center on the centroid, optionally rotate the first point to
+/// angle zero, and scale to fit the unit square keeping the aspect ratio, so
+/// that straight strokes don't blow up. `None` if the trail has no length.
+pub fn normalize(trail: &[Point], rotation_invariant: bool) -> Option<Vec<Point>> {
+    let points = resample(trail, RESAMPLE_POINTS)?;
+    let centroid = centroid(&points);
+    let mut points = translate(&points, (-centroid.0, -centroid.1));
+    if rotation_invariant {
+        let angle = points[0].1.atan2(points[0].0);
+        points = rotate(&points, -angle);
+    }
+    let (min_x, max_x, min_y, max_y) = points.iter().fold(
+        (f64::MAX, f64::MIN, f64::MAX, f64::MIN),
+        |(min_x, max_x, min_y, max_y), (x, y)| {
+            (min_x.min(*x), max_x.max(*x), min_y.min(*y), max_y.max(*y))
+        },
+    );
+    let size = (max_x - min_x).max(max_y - min_y);
+    if size <= f64::EPSILON {
+        return None;
+    }
+    Some(points.iter().map(|(x, y)| (x / size, y / size)).collect())
+}
+
+fn resample(trail: &[Point], n: usize) -> Option<Vec<Point>> {
+    let length = trail.windows(2).map(|w| dist(w[0], w[1])).sum::<f64>();
+    if length <= f64::EPSILON {
+        return None;
+    }
+    let interval = length / (n - 1) as f64;
+    let mut out = vec![trail[0]];
+    let mut acc = 0.0;
+    let mut prev = trail[0];
+    let mut rest = trail[1..].iter().copied();
+    let mut next = rest.next();
+    while let Some(cur) = next {
+        let d = dist(prev, cur);
+        if acc + d >= interval && d > 0.0 {
+            let t = (interval - acc) / d;
+            let point = (prev.0 + t * (cur.0 - prev.0), prev.1 + t * (cur.1 - prev.1));
+            out.push(point);
+            // continue from the new point towards the same `cur`
+            prev = point;
+            acc = 0.0;
+        } else {
+            acc += d;
+            prev = cur;
+            next = rest.next();
+        }
+    }
+    // rounding may leave us a point short
+    out.resize(n, *trail.last()?);
+    out.truncate(n);
+    Some(out)
+}
+
+fn centroid(points: &[Point]) -> Point {
+    let n = points.len() as f64;
+    let (x, y) = points
+        .iter()
+        .fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+    (x / n, y / n)
+}
+
+fn translate(points: &[Point], by: Point) -> Vec<Point> {
+    points.iter().map(|(x, y)| (x + by.0, y + by.1)).collect()
+}
+
+fn rotate(points: &[Point], angle: f64) -> Vec<Point> {
+    let (sin, cos) = angle.sin_cos();
+    points
+        .iter()
+        .map(|(x, y)| (x * cos - y * sin, x * sin + y * cos))
+        .collect()
+}
+
+fn dist(a: Point, b: Point) -> f64 {
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+fn path_distance(a: &[Point], b: &[Point]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| dist(*a, *b)).sum::<f64>() / a.len() as f64
+}
+
+/// Golden section search for the angle minimizing the path distance.
+fn distance_at_best_angle(candidate: &[Point], template: &[Point]) -> f64 {
+    let phi = 0.5 * (5f64.sqrt() - 1.0);
+    let at = |angle| path_distance(&rotate(candidate, angle), template);
+    let (mut a, mut b) = (-ANGLE_RANGE, ANGLE_RANGE);
+    let mut x1 = phi * a + (1.0 - phi) * b;
+    let mut f1 = at(x1);
+    let mut x2 = (1.0 - phi) * a + phi * b;
+    let mut f2 = at(x2);
+    while (b - a).abs() > ANGLE_PRECISION {
+        if f1 < f2 {
+            b = x2;
+            x2 = x1;
+            f2 = f1;
+            x1 = phi * a + (1.0 - phi) * b;
+            f1 = at(x1);
+        } else {
+            a = x1;
+            x1 = x2;
+            f1 = f2;
+            x2 = (1.0 - phi) * a + phi * b;
+            f2 = at(x2);
+        }
+    }
+    f1.min(f2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GestureConfig;
+
+    /// Strokes written like single-stroke patterns separated by spaces, e.g.
+    /// `R:long U`; a missing qualifier is an unknown class.
+    fn strokes(source: &str) -> Vec<Stroke> {
+        source
+            .split_whitespace()
+            .map(|x| {
+                let pattern = GesturePattern::parse(x).unwrap();
+                let [Token::One(stroke)] = pattern.tokens() else {
+                    panic!("not a single stroke: {x}");
+                };
+                *stroke
+            })
+            .collect()
+    }
+
+    fn pattern(source: &str) -> GesturePattern {
+        GesturePattern::parse(source).unwrap()
+    }
+
+    #[test]
+    fn parse_qualifiers() {
+        let pattern = pattern("R:long:fastU:short+");
+        assert_eq!(
+            pattern.tokens(),
+            [
+                Token::One(Stroke {
+                    dir: GestureDir::R,
+                    length: Some(Length::Long),
+                    speed: Some(Speed::Fast),
+                }),
+                Token::OneOrMore(Stroke {
+                    dir: GestureDir::U,
+                    length: Some(Length::Short),
+                    speed: None,
+                }),
+            ]
+        );
+        assert!(pattern.uses_length());
+        assert!(pattern.uses_speed());
+        assert!(!pattern.is_exact());
+    }
+
+    #[test]
+    fn parse_shape() {
+        assert_eq!(pattern("@circle_cw-2").shape(), Some("circle_cw-2"));
+        assert!(pattern("@circle").tokens().is_empty());
+    }
+
+    #[test]
+    fn parse_errors() {
+        for source in [
+            "@",
+            "@a b",
+            "x",
+            ":long",
+            "R:long:short",
+            "R:fast:slow",
+            "R:big",
+            "+",
+            "*+",
+            "R+:long",
+            "**",
+            "R:",
+            "RR",
+            "R+R",
+            "UR:short+R:long",
+            "UDD+",
+        ] {
+            assert!(GesturePattern::parse(source).is_err(), "{source:?}");
+        }
+        // `*` may repeat as long as something is in between, and so may
+        // directions.
+        assert!(GesturePattern::parse("*U*").is_ok());
+        assert!(GesturePattern::parse("R*R").is_ok());
+    }
+
+    #[test]
+    fn matches_exact() {
+        let pattern = pattern("RU");
+        assert!(pattern.matches(&strokes("R U")));
+        assert!(!pattern.matches(&strokes("R")));
+        assert!(!pattern.matches(&strokes("R U U")));
+        assert!(!pattern.matches(&strokes("U R")));
+        assert!(!pattern.matches(&[]));
+    }
+
+    #[test]
+    fn matches_qualifiers() {
+        let pattern = pattern("R:long:fast");
+        assert!(pattern.matches(&strokes("R:long:fast")));
+        // Unknown classes match any qualifier.
+        assert!(pattern.matches(&strokes("R")));
+        assert!(pattern.matches(&strokes("R:long")));
+        assert!(!pattern.matches(&strokes("R:short:fast")));
+        assert!(!pattern.matches(&strokes("R:long:slow")));
+        // Unqualified patterns match any class.
+        assert!(self::pattern("R").matches(&strokes("R:short:slow")));
+    }
+
+    #[test]
+    fn matches_repetition() {
+        // strokes merge, so `R+` is the same as `R`
+        let pattern = pattern("UR+D");
+        assert!(pattern.matches(&strokes("U R D")));
+        assert!(!pattern.matches(&strokes("U D")));
+        assert!(!pattern.matches(&strokes("U R")));
+        assert!(self::pattern("R:long+").matches(&strokes("R:long")));
+        assert!(!self::pattern("R:long+").matches(&strokes("R:short")));
+    }
+
+    #[test]
+    fn matches_any() {
+        assert!(pattern("*").matches(&[]));
+        assert!(pattern("*").matches(&strokes("U D L R")));
+        let prefix = pattern("UD*");
+        assert!(prefix.matches(&strokes("U D")));
+        assert!(prefix.matches(&strokes("U D L R")));
+        assert!(!prefix.matches(&strokes("D U")));
+        let suffix = pattern("*LR");
+        assert!(suffix.matches(&strokes("L R")));
+        assert!(suffix.matches(&strokes("U D L R")));
+        assert!(!suffix.matches(&strokes("L R U")));
+        assert!(pattern("U*R").matches(&strokes("U L D R")));
+    }
+
+    #[test]
+    fn shapes_match_no_strokes() {
+        let pattern = pattern("@circle");
+        assert!(!pattern.matches(&[]));
+        assert!(!pattern.matches(&strokes("U")));
+        assert!(pattern.reachable(&strokes("U D L R")));
+    }
+
+    #[test]
+    fn reachable() {
+        let pattern = pattern("UR:long+D");
+        assert!(pattern.reachable(&[]));
+        assert!(pattern.reachable(&strokes("U")));
+        assert!(pattern.reachable(&strokes("U R")));
+        assert!(pattern.reachable(&strokes("U R D")));
+        assert!(!pattern.reachable(&strokes("U R:short")));
+        assert!(!pattern.reachable(&strokes("U R D L")));
+        assert!(!pattern.reachable(&strokes("D")));
+        assert!(self::pattern("U*").reachable(&strokes("U L D")));
+        assert!(!self::pattern("U*").reachable(&strokes("L")));
+        assert!(self::pattern("*U").reachable(&strokes("L D")));
+        assert!(!self::pattern("RU").reachable(&strokes("R U L")));
+    }
+
+    #[test]
+    fn priority_order() {
+        let mut patterns = ["*", "U*", "U+", "@circle", "UD", "*D", "UD:long"].map(pattern);
+        patterns.sort_by_key(GesturePattern::priority);
+        assert_eq!(
+            patterns.each_ref().map(GesturePattern::source),
+            ["UD:long", "UD", "@circle", "U+", "*D", "U*", "*"]
+        );
+    }
+
+    #[test]
+    fn priority_ties_break_by_source() {
+        let mut patterns = ["UR", "LD", "DU"].map(pattern);
+        patterns.sort_by_key(GesturePattern::priority);
+        assert_eq!(
+            patterns.each_ref().map(GesturePattern::source),
+            ["DU", "LD", "UR"]
+        );
+    }
+
+    #[test]
+    fn distance() {
+        let pattern = pattern("URD");
+        assert_eq!(pattern.distance(&strokes("U R D")), Some(0));
+        assert_eq!(pattern.distance(&strokes("U L D")), Some(1));
+        assert_eq!(pattern.distance(&strokes("U D")), Some(1));
+        assert_eq!(pattern.distance(&strokes("U R L D")), Some(1));
+        assert_eq!(pattern.distance(&strokes("D R U")), Some(2));
+        assert_eq!(pattern.distance(&[]), Some(3));
+        assert_eq!(
+            self::pattern("R:long").distance(&strokes("R:short")),
+            Some(1)
+        );
+        assert_eq!(self::pattern("R+").distance(&strokes("R")), None);
+        assert_eq!(self::pattern("@circle").distance(&strokes("R")), None);
+    }
+
+    /// Points along the lines between `corners`, as a pointer would report
+    /// them, scaled and moved away from the templates' coordinates.
+    fn polyline(corners: &[Point]) -> Vec<Point> {
+        let mut trail = vec![];
+        for w in corners.windows(2) {
+            for i in 0..10 {
+                let t = f64::from(i) / 10.0;
+                trail.push((
+                    300.0 + 40.0 * (w[0].0 + t * (w[1].0 - w[0].0)),
+                    200.0 + 40.0 * (w[0].1 + t * (w[1].1 - w[0].1)),
+                ));
+            }
+        }
+        trail.extend(
+            corners
+                .last()
+                .map(|(x, y)| (300.0 + 40.0 * x, 200.0 + 40.0 * y)),
+        );
+        trail
+    }
+
+    /// A circle starting at `start` radians, clockwise on screen for a
+    /// positive `sign`.
+    fn circle(sign: f64, start: f64) -> Vec<Point> {
+        (0..=40)
+            .map(|i| {
+                let t = start + sign * 2.0 * PI * f64::from(i) / 40.0;
+                (500.0 + 120.0 * t.cos(), 300.0 + 120.0 * t.sin())
+            })
+            .collect()
+    }
+
+    fn min_score() -> f64 {
+        GestureConfig::default().min_shape_score
+    }
+
+    #[test]
+    fn normalize_fits_unit_square() {
+        let points = normalize(&polyline(&[(0.0, 0.0), (4.0, 2.0)]), false).unwrap();
+        assert_eq!(points.len(), RESAMPLE_POINTS);
+        let (x, y) = centroid(&points);
+        assert!(x.abs() < 1e-9 && y.abs() < 1e-9);
+        let width = points.iter().map(|x| x.0).fold(f64::MIN, f64::max)
+            - points.iter().map(|x| x.0).fold(f64::MAX, f64::min);
+        assert!((width - 1.0).abs() < 1e-9);
+        assert_eq!(normalize(&[(1.0, 1.0), (1.0, 1.0)], false), None);
+        assert_eq!(normalize(&[(1.0, 1.0)], false), None);
+    }
+
+    #[test]
+    fn resample_spaces_points_evenly() {
+        let points = resample(&[(0.0, 0.0), (1.0, 0.0), (1.0, 3.0)], 5).unwrap();
+        assert_eq!(points.len(), 5);
+        for w in points.windows(2) {
+            assert!((dist(w[0], w[1]) - 1.0).abs() < 1e-9, "{points:?}");
+        }
+        assert_eq!(resample(&[(2.0, 2.0)], 5), None);
+    }
+
+    #[test]
+    fn circles_match_their_direction() {
+        let shapes = Shapes::default();
+        for start in [0.0, 1.0, PI, 4.5] {
+            let cw = circle(1.0, start);
+            assert!(shapes.score("circle_cw", &cw) >= min_score(), "{start}");
+            assert!(shapes.score("circle_ccw", &cw) < min_score(), "{start}");
+            let ccw = circle(-1.0, start);
+            assert!(shapes.score("circle_ccw", &ccw) >= min_score(), "{start}");
+            assert!(shapes.score("circle_cw", &ccw) < min_score(), "{start}");
+        }
+    }
+
+    #[test]
+    fn polylines_match_themselves() {
+        let shapes = Shapes::default();
+        let check = polyline(&[(0.0, 0.0), (1.0, 1.0), (3.0, -2.0)]);
+        assert!(shapes.score("check", &check) >= min_score());
+        assert!(shapes.score("zigzag", &check) < min_score());
+        let zigzag = polyline(&[
+            (0.0, 0.0),
+            (1.0, 2.0),
+            (2.0, 0.0),
+            (3.0, 2.0),
+            (4.0, 0.0),
+            (5.0, 2.0),
+        ]);
+        assert!(shapes.score("zigzag", &zigzag) >= min_score());
+        assert!(shapes.score("check", &zigzag) < min_score());
+    }
+
+    /// Horizontal lines come close to `zigzag`, at about 0.79.
+    #[test]
+    fn lines_match_no_shape() {
+        let shapes = Shapes::default();
+        for end in [(5.0, 0.0), (5.0, 2.0), (0.0, 5.0), (-3.0, 3.0)] {
+            let line = polyline(&[(0.0, 0.0), end]);
+            for name in ["circle_cw", "circle_ccw", "check", "zigzag"] {
+                let score = shapes.score(name, &line);
+                assert!(score < min_score(), "{end:?} {name} {score}");
+            }
+        }
+    }
+
+    #[test]
+    fn record() {
+        let dir = std::env::temp_dir().join(format!("tweakpoint-test-{}", std::process::id()));
+        let path = dir.join("shapes.toml");
+        let mut shapes = Shapes::load(path.clone());
+        let trail = polyline(&[(0.0, 0.0), (2.0, 0.0), (0.0, 2.0)]);
+        shapes.record("z", &trail);
+        assert!(shapes.contains("z"));
+        assert!(shapes.score("z", &trail) > 0.99);
+        // too small to record
+        shapes.record("dot", &[(1.0, 1.0)]);
+        assert!(!shapes.contains("dot"));
+
+        let loaded = Shapes::load(path);
+        assert!(loaded.contains("z"));
+        assert!(loaded.score("z", &trail) > 0.99);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
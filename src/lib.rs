@@ -0,0 +1,4 @@
+//! Protocol and clients for the tweakpoint state socket.
+
+pub mod client;
+pub mod protocol;
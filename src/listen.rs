@@ -0,0 +1,41 @@
+//! systemd socket activation, see `sd_listen_fds(3)`.
+
+use std::os::fd::{FromRawFd, RawFd};
+
+/// First file descriptor passed by systemd.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Name of the socket we look for in `LISTEN_FDNAMES`. If there are no names,
+/// or none matches, the first passed socket is used.
+const FD_NAME: &str = "tweakpoint";
+
+/// Take the control socket passed by systemd, if any.
+pub fn listen_socket() -> std::io::Result<Option<tokio::net::UnixListener>> {
+    let Some(pid) = std::env::var("LISTEN_PID").ok() else {
+        return Ok(None);
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        tracing::debug!(%pid, "LISTEN_PID is not ours, ignoring passed sockets");
+        return Ok(None);
+    }
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|x| x.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    if count <= 0 {
+        return Ok(None);
+    }
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let idx = names
+        .split(':')
+        .take(count as usize)
+        .position(|name| name == FD_NAME)
+        .unwrap_or(0);
+    let fd = SD_LISTEN_FDS_START + idx as RawFd;
+    tracing::debug!(fd, count, %names, "Using socket passed by systemd");
+    // SAFETY: systemd passes us ownership of LISTEN_FDS descriptors starting
+    // at SD_LISTEN_FDS_START, and nothing else claims them.
+    let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(tokio::net::UnixListener::from_std(listener)?))
+}
@@ -1,8 +1,18 @@
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant, SystemTime},
+};
+
 use evdev::{EventType, InputEvent, KeyCode, RelativeAxisCode};
 
+use serde::Deserialize;
+use tweakpoint::protocol::{self, LockStep};
+
 use crate::{
-    config::{Config, Direction},
-    state::{ActionType, GestureDir, State},
+    config::{Action, ButtonMapping, Config, Direction},
+    gesture::Shapes,
+    persist::PersistedState,
+    state::{ActionType, PressedButton, State},
 };
 
 pub struct Controller {
@@ -10,43 +20,125 @@ pub struct Controller {
     config: Config,
     synthetic_tx: tokio::sync::mpsc::UnboundedSender<InputEvent>,
     synthetic_rx: tokio::sync::mpsc::UnboundedReceiver<InputEvent>,
+    /// Keys that are logically pressed on the virtual device.
+    pressed: BTreeSet<KeyCode>,
 }
 
 impl Controller {
     pub fn new(config: Config) -> Self {
         let (synthetic_tx, synthetic_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut state = State::default();
+        state.autofire.min_interval = min_autofire_interval(&config);
+        state.speed.levels = config.speed_levels.clone();
+        state.click_lock.configure(&config.click_lock);
+        state.gesture_config = config.gesture.clone();
+        state.shapes = load_shapes(&config);
         Self {
-            state: State::default(),
+            state,
             config,
             synthetic_rx,
             synthetic_tx,
+            pressed: BTreeSet::new(),
+        }
+    }
+
+    /// Replace the config. Device-level settings, i.e. the physical devices,
+    /// the virtual device identity and the socket, only apply on restart.
+    pub fn reload(&mut self, config: Config) {
+        self.state.autofire.min_interval = min_autofire_interval(&config);
+        self.state.speed.levels = config.speed_levels.clone();
+        if self.state.speed.level.is_some() {
+            // levels may have changed under us
+            self.state.set_speed_level(None);
+        }
+        let evts = self
+            .state
+            .click_lock
+            .state_vec()
+            .filter(|(_, step)| matches!(step, LockStep::Locked))
+            .map(|(key, _)| InputEvent::new(EventType::KEY.0, key.0, 0))
+            .collect::<Vec<_>>();
+        self.send_events(evts);
+        self.state.click_lock.configure(&config.click_lock);
+        self.state.gesture_config = config.gesture.clone();
+        self.state.shapes = load_shapes(&config);
+        self.config = config;
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn state(&self) -> protocol::State {
+        let gesture = self.state.gesture.as_ref().filter(|x| !x.cancelled);
+        protocol::State {
+            scroll_active: self.state.scroll.active,
+            buttons: self
+                .state
+                .lock
+                .state_vec()
+                .chain(self.state.click_lock.state_vec())
+                .collect(),
+            gesture: gesture.map(|x| x.dirs()).unwrap_or_default(),
+            slow: self.state.slow.unwrap_or(1.0),
+            speed_level: self.state.speed.level,
+            last_gesture: self.state.last_gesture,
+            trail: gesture
+                .map(|x| x.published_trail.clone())
+                .unwrap_or_default(),
+            candidates: gesture.map(|x| x.candidates.clone()).unwrap_or_default(),
         }
     }
 
-    pub fn state_vec(&self, out: &mut Vec<u8>) {
-        fn patchback(out: &mut Vec<u8>, action: impl FnOnce(&mut Vec<u8>)) {
-            out.extend_from_slice(&[0x00; 4]);
-            let pos = out.len();
-            action(out);
-            let len = (out.len() - pos) as u32;
-            out[pos - 4..pos].copy_from_slice(&len.to_le_bytes());
+    pub fn persisted_state(&self) -> PersistedState {
+        let persist = &self.config.persist;
+        PersistedState {
+            scroll: persist.scroll.then_some(self.state.scroll.base()),
+            slow: self.state.slow.filter(|_| persist.slow),
+            speed_level: self.state.speed.level.filter(|_| persist.slow),
+        }
+    }
+
+    pub fn restore(&mut self, persisted: PersistedState) {
+        let persist = &self.config.persist;
+        if persist.scroll
+            && let Some(active) = persisted.scroll
+            && active != self.state.scroll.active
+        {
+            self.state.scroll.toggle();
         }
-        patchback(out, |out| {
-            let scroll_active = self.state.scroll.active;
-            out.push(if scroll_active { 0x01 } else { 0x00 });
-            patchback(out, |out| {
-                for (lock_btn, lock_step) in self.state.lock.state_vec() {
-                    out.extend_from_slice(&lock_btn.0.to_le_bytes());
-                    out.push(lock_step as u8);
+        if persist.slow {
+            match persisted.speed_level {
+                Some(level) if self.state.speed.levels.get(level) == persisted.slow.as_ref() => {
+                    self.state.set_speed_level(Some(level))
                 }
-            });
-            patchback(out, |out| {
-                for dir in self.state.gesture_dir.iter().flatten() {
-                    out.push(*dir as u8);
+                _ => self.state.slow = persisted.slow,
+            }
+        }
+    }
+
+    pub fn command(&mut self, command: protocol::Command) {
+        match command {
+            protocol::Command::Run(action) => {
+                let action = match Action::deserialize(toml::de::ValueDeserializer::new(&action)) {
+                    Ok(action) => action,
+                    Err(error) => {
+                        tracing::error!(%error, %action, "Invalid action in command");
+                        return;
+                    }
+                };
+                if let Err(error) = self.config.validate_action(&action) {
+                    tracing::error!(%error, ?action, "Invalid action in command");
+                    return;
                 }
-            });
-            out.extend_from_slice(&self.state.slow.unwrap_or(1.0).to_le_bytes())
-        });
+                let evts = action
+                    .run(&mut self.state, Direction::Down, "Command")
+                    .into_iter()
+                    .chain(action.run(&mut self.state, Direction::Up, "Command"))
+                    .collect::<Vec<_>>();
+                self.send_events(evts);
+            }
+        }
     }
 
     fn send_events(&self, it: impl IntoIterator<Item = InputEvent>) {
@@ -68,12 +160,131 @@ impl Controller {
                   let evts = self.config.meta.hold.run(&mut self.state, Direction::Down, "Hold fired");
                   self.send_events(evts);
               },
+              _ = self.state.click_lock.wait() => {
+                  self.state.click_lock.tick();
+              },
+              _ = self.state.lock.wait() => {
+                  let evts = self.state.lock.tick();
+                  self.send_events(evts);
+              },
+              _ = self.state.autofire.wait() => {
+                  let evts = self.state.autofire.tick();
+                  self.send_events(evts);
+              },
+              _ = self.state.sequence.wait() => {
+                  let evts = self.state.advance_sequence();
+                  self.send_events(evts);
+              },
               n = self.synthetic_rx.recv_many(buf, usize::MAX) => {
+                  self.track_pressed(&buf[buf.len() - n..]);
                   return n;
               }
             }
         }
     }
+
+    fn track_pressed(&mut self, evts: &[InputEvent]) {
+        for evt in evts {
+            if evt.event_type() == EventType::KEY {
+                match evt.value() {
+                    0 => self.pressed.remove(&KeyCode(evt.code())),
+                    1 => self.pressed.insert(KeyCode(evt.code())),
+                    _ => continue,
+                };
+            }
+        }
+    }
+
+    /// Reset state tied to the disconnected device, then collect events like
+    /// [`Self::release_all`].
+    pub fn disconnected(&mut self, buf: &mut Vec<InputEvent>) {
+        let evts = self.state.reset_input();
+        self.send_events(evts);
+        self.release_all(buf);
+    }
+
+    /// Collect pending events, followed by releases for all logically pressed
+    /// keys. Meant to be called once input stopped, on shutdown or when the
+    /// device disconnected.
+    pub fn release_all(&mut self, buf: &mut Vec<InputEvent>) {
+        let start = buf.len();
+        while let Ok(evt) = self.synthetic_rx.try_recv() {
+            buf.push(evt);
+        }
+        self.track_pressed(&buf[start..]);
+        for key in std::mem::take(&mut self.pressed) {
+            tracing::debug!(?key, "Releasing logically pressed key");
+            buf.push(InputEvent::new(EventType::KEY.0, key.0, 0));
+        }
+    }
+
+    /// Resolve a physical button through the active layers, falling back to
+    /// the base map.
+    fn resolve(&self, key_code: KeyCode) -> PressedButton {
+        let mut layers = self
+            .state
+            .layers
+            .active()
+            .filter_map(|name| self.config.layers.get(name));
+        let mapping = layers
+            .clone()
+            .find_map(|layer| layer.btn_map.get(&key_code))
+            .or_else(|| self.config.btn_map.get(&key_code))
+            .cloned();
+        let mapped_key = match &mapping {
+            Some(ButtonMapping::Key(new)) => {
+                tracing::debug!(orig = ?key_code, ?new, "Mapped key press");
+                *new
+            }
+            _ => key_code,
+        };
+        let chord = layers
+            .find_map(|layer| layer.chord.get(&mapped_key))
+            .or_else(|| self.config.meta.chord.get(&mapped_key))
+            .cloned();
+        PressedButton {
+            mapping,
+            mapped_key,
+            chord,
+            chorded: false,
+            pressed_at: Instant::now(),
+        }
+    }
+}
+
+fn load_shapes(config: &Config) -> Shapes {
+    let shapes = Shapes::load(config.gesture.shapes.clone());
+    for action in config.actions() {
+        action.walk(&mut |action| {
+            if let Action::Gesture(gestures) = action {
+                for name in gestures.shapes() {
+                    if !shapes.contains(name) {
+                        tracing::warn!(name, "Unknown shape in gesture key, record it first");
+                    }
+                }
+            }
+        });
+    }
+    shapes
+}
+
+/// The same wheel at the other resolution.
+fn hi_res_counterpart(axis: RelativeAxisCode) -> Option<RelativeAxisCode> {
+    Some(match axis {
+        RelativeAxisCode::REL_WHEEL => RelativeAxisCode::REL_WHEEL_HI_RES,
+        RelativeAxisCode::REL_WHEEL_HI_RES => RelativeAxisCode::REL_WHEEL,
+        RelativeAxisCode::REL_HWHEEL => RelativeAxisCode::REL_HWHEEL_HI_RES,
+        RelativeAxisCode::REL_HWHEEL_HI_RES => RelativeAxisCode::REL_HWHEEL,
+        _ => return None,
+    })
+}
+
+fn min_autofire_interval(config: &Config) -> Duration {
+    config
+        .autofire_max_rate
+        .filter(|x| *x > 0.0)
+        .map(|x| Duration::from_secs_f64(1.0 / x))
+        .unwrap_or_default()
 }
 
 pub struct Transaction<'a> {
@@ -108,30 +319,54 @@ impl<'a> Transaction<'a> {
             // don't pass go, don't pass through meta key.
             return;
         }
-        let mapped_key = ctl
-            .config
-            .btn_map
-            .get(&key_code)
-            .inspect(|new| {
-                tracing::debug!(orig = ?key_code, ?new, "Mapped key press");
-            })
-            .unwrap_or(&key_code);
-        if let Some(action) = ctl.config.meta.chord.get(mapped_key) {
-            if ctl
-                .state
-                .meta_down
-                .activate_waiting(ActionType::Chord(*mapped_key))
+        // releases go through the same mapping as the press, even if layers
+        // changed in between.
+        let mut pressed = match value {
+            1 => None,
+            0 => ctl.state.pressed_buttons.remove(&key_code),
+            _ => ctl.state.pressed_buttons.get(&key_code).cloned(),
+        }
+        .unwrap_or_else(|| {
+            if value == 1
+                && ctl.config.gesture.cancel_on_button
+                && ctl.state.gesture.as_ref().is_some_and(|x| !x.cancelled)
+            {
+                ctl.state.cancel_gesture("cancel_on_button");
+                PressedButton::swallowed(key_code)
+            } else {
+                ctl.resolve(key_code)
+            }
+        });
+        if value == 1
+            && let Some(layer) = ctl.state.layers.take_one_shot()
+        {
+            tracing::debug!(%layer, "One-shot layer consumed");
+        }
+        self.mapped_button(value, &mut pressed);
+        if value != 0 {
+            self.ctl.state.pressed_buttons.insert(key_code, pressed);
+        }
+    }
+
+    fn mapped_button(&mut self, value: i32, pressed: &mut PressedButton) {
+        let ctl = &mut self.ctl;
+        let mapped_key = &pressed.mapped_key;
+        let dir = if matches!(value, 1) {
+            Direction::Down
+        } else {
+            Direction::Up
+        };
+        if let Some(action) = &pressed.chord {
+            if pressed.chorded
+                || (value == 1
+                    && ctl
+                        .state
+                        .meta_down
+                        .activate_waiting(ActionType::Chord(*mapped_key)))
             {
                 tracing::debug!(key = ?mapped_key, "Activated chord");
-                let evts = action.run(
-                    &mut ctl.state,
-                    if matches!(value, 1) {
-                        Direction::Down
-                    } else {
-                        Direction::Up
-                    },
-                    "Chord activated",
-                );
+                pressed.chorded = true;
+                let evts = action.run(&mut ctl.state, dir, "Chord activated");
                 ctl.send_events(evts);
                 // don't pass go, don't emit the chorded button.
                 return;
@@ -145,13 +380,83 @@ impl<'a> Transaction<'a> {
             ctl.send_events(evts);
         }
 
-        if let Some(mapped_key) = ctl.state.lock.check(mapped_key, value) {
+        let evts = ctl.state.lock.on_button(mapped_key, value);
+        ctl.send_events(evts);
+
+        if let Some(ButtonMapping::Action(action)) = &pressed.mapping {
+            tracing::debug!(?mapped_key, ?action, "Mapped key to action");
+            let evts = action.run(&mut ctl.state, dir, "Button mapped to action");
+            ctl.send_events(evts);
+            return;
+        }
+
+        if let Some(mapped_key) = ctl.state.click_lock.check(mapped_key, value)
+            && let Some(mapped_key) = ctl.state.lock.check(&mapped_key, value)
+        {
             ctl.send_events([InputEvent::new(EventType::KEY.0, mapped_key.0, value)]);
         }
     }
 
-    pub fn relative(&mut self, axis: RelativeAxisCode, value: i32) {
+    /// Handle a key event from the secondary keyboard. Returns `false` if the
+    /// key isn't handled by us and should be passed through.
+    pub fn keyboard(&mut self, key_code: KeyCode, value: i32) -> bool {
+        let ctl = &mut self.ctl;
+        let Some(keyboard) = &ctl.config.keyboard else {
+            return false;
+        };
+        if let Some(action) = keyboard.bind.get(&key_code) {
+            // ignore autorepeat
+            if value != 2 {
+                let evts = action.run(
+                    &mut ctl.state,
+                    if matches!(value, 1) {
+                        Direction::Down
+                    } else {
+                        Direction::Up
+                    },
+                    "Keyboard key",
+                );
+                ctl.send_events(evts);
+            }
+            true
+        } else if keyboard.grab && keyboard.forward.contains(&key_code) {
+            if value != 2 {
+                self.button(key_code, value);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn relative(&mut self, axis: RelativeAxisCode, value: i32, time: SystemTime) {
         let ctl = &mut self.ctl;
+        if let Some(trigger) = ctl.config.axis_triggers.get(&axis)
+            && let Some(action) = trigger.action(value)
+        {
+            let count = ctl
+                .state
+                .axis_triggers
+                .accumulate(axis, value, time, trigger);
+            for _ in 0..count {
+                tracing::debug!(?axis, value, ?action, "Axis trigger");
+                let evts = action
+                    .run(&mut ctl.state, Direction::Down, "Axis trigger down")
+                    .into_iter()
+                    .chain(action.run(&mut ctl.state, Direction::Up, "Axis trigger up"))
+                    .collect::<Vec<_>>();
+                ctl.send_events(evts);
+            }
+            return;
+        }
+        if !ctl.config.axis_triggers.contains_key(&axis)
+            && let Some(trigger) =
+                hi_res_counterpart(axis).and_then(|x| ctl.config.axis_triggers.get(&x))
+            && trigger.action(value).is_some()
+        {
+            // the other resolution of a triggered wheel
+            return;
+        }
         if ctl.state.meta_down.activate_waiting(ActionType::Move) {
             let evts =
                 ctl.config
@@ -161,23 +466,50 @@ impl<'a> Transaction<'a> {
             ctl.send_events(evts);
         }
 
+        ctl.state.lock.on_move();
+
         // this is a little weird: use remapped axes for gestures, but ignore
         // scroll toggle and axis factors. There may be a more natural option
         // hiding here somewhere but I don't see it.
-        match ctl.config.axis_map.get(axis, false).axis {
+        match ctl.config.axis_map.get(axis, false, None).axis {
             RelativeAxisCode::REL_X => self.relative_movement.0 += value,
             RelativeAxisCode::REL_Y => self.relative_movement.1 += value,
             _ => {}
         }
 
-        let new_axis = ctl.config.axis_map.get(axis, ctl.state.scroll.active);
+        // the most recently pressed button or chord with a table wins
+        let scoped = ctl
+            .state
+            .pressed_buttons
+            .iter()
+            .filter_map(|(key, pressed)| {
+                let table = if pressed.chorded {
+                    ctl.config.axis_map.chord.get(&pressed.mapped_key)
+                } else {
+                    ctl.config.axis_map.held.get(key)
+                }?;
+                Some((pressed.pressed_at, table))
+            })
+            .max_by_key(|(pressed_at, _)| *pressed_at)
+            .map(|(_, table)| table);
+        let new_axis = ctl
+            .config
+            .axis_map
+            .get(axis, ctl.state.scroll.active, scoped);
+        let acceleration = match new_axis.acceleration {
+            Some(acceleration) => ctl
+                .state
+                .scroll
+                .accelerate(axis, value, time, &acceleration),
+            _ => 1.0,
+        };
         let new_value = ctl.state.scroll.scroll(
             new_axis.axis,
             value,
-            ctl.state.slow.unwrap_or(1.0) * new_axis.factor,
+            ctl.state.slow.unwrap_or(1.0) * new_axis.factor * acceleration,
         );
 
-        if ctl.config.move_during_gesture || ctl.state.gesture_dir.is_none() {
+        if ctl.config.move_during_gesture || !ctl.state.gesture_active() {
             ctl.send_events([InputEvent::new(
                 EventType::RELATIVE.0,
                 new_axis.axis.0,
@@ -197,32 +529,31 @@ impl Drop for Transaction<'_> {
             ctl,
             relative_movement,
         } = self;
-        if relative_movement.0.unsigned_abs() <= ctl.config.min_gesture_movement
-            && relative_movement.1.unsigned_abs() <= ctl.config.min_gesture_movement
-        {
-            // movement is insignificant
-            return;
-        }
-        if let Some(gesture_dir) = &mut ctl.state.gesture_dir {
-            tracing::trace!(?relative_movement, "Relative movement");
-            let dir = if relative_movement.0.abs() > relative_movement.1.abs() {
-                // X axis
-                if relative_movement.0 > 0 {
-                    GestureDir::R
-                } else {
-                    GestureDir::L
-                }
-            } else {
-                // Y axis
-                if relative_movement.1 > 0 {
-                    GestureDir::D
-                } else {
-                    GestureDir::U
-                }
-            };
-            if gesture_dir.last() != Some(&dir) {
-                gesture_dir.push(dir);
-            }
-        }
+        let evts = ctl.state.continuous_gesture_move(*relative_movement);
+        ctl.send_events(evts);
+        let significant = relative_movement.0.unsigned_abs() > ctl.config.min_gesture_movement
+            || relative_movement.1.unsigned_abs() > ctl.config.min_gesture_movement;
+        tracing::trace!(?relative_movement, significant, "Relative movement");
+        ctl.state.gesture_move(*relative_movement, significant);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scroll_hold_is_not_persisted() {
+        let mut config = Config::default();
+        config.persist.scroll = true;
+        let mut controller = Controller::new(config);
+        controller.state.scroll.hold();
+        assert!(controller.state.scroll.active);
+        assert_eq!(controller.persisted_state().scroll, Some(false));
+
+        controller.state.scroll.release();
+        controller.state.scroll.set(true);
+        controller.state.scroll.hold();
+        assert_eq!(controller.persisted_state().scroll, Some(true));
     }
 }
@@ -1,18 +1,32 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use clap::Parser;
 use evdev::{
-    AttributeSet, BusType, Device, EventType, InputId, KeyCode, MiscCode, PropType,
-    RelativeAxisCode, SynchronizationCode, UinputAbsSetup, uinput::VirtualDevice,
+    AttributeSet, BusType, Device, EventStream, EventType, InputEvent, InputId, KeyCode, MiscCode,
+    PropType, RelativeAxisCode, SynchronizationCode, UinputAbsSetup,
+    uinput::{VirtualDevice, VirtualEventStream},
 };
 use figment::providers::Format;
+use futures::{FutureExt, future::Fuse};
+use tokio::signal::unix::{SignalKind, signal};
+use tweakpoint::protocol::{Command, frame_len};
 
-use self::{config::*, logic::*, notify::SdNotify};
+use self::{
+    config::*, listen::listen_socket, logic::*, notify::SdNotify, persist::Persister,
+    uinput::LedKeyboard,
+};
 
 mod config;
+mod gesture;
+mod listen;
 mod logic;
 mod notify;
+mod persist;
 mod state;
+mod uinput;
 mod utils;
 
 #[derive(clap::Parser)]
@@ -78,124 +92,618 @@ async fn main() -> anyhow::Result<()> {
         return Ok(());
     }
 
-    let config: Config = figment::Figment::new()
-        .join(figment::providers::Toml::file(&cli.config))
-        .extract()?;
+    let config = load_config(&cli.config)?;
 
     if cli.dump_config {
         println!("{}", toml::to_string(&config)?);
         return Ok(());
     }
 
-    const MIN_BACKOFF: Duration = Duration::from_millis(1);
-    const MAX_BACKOFF: Duration = Duration::from_secs(1);
-
-    let mut backoff = MIN_BACKOFF;
-    let device = loop {
-        let mut device = match Device::open(&config.device) {
-            Ok(x) => x,
-            Err(error) => {
-                tracing::error!(%error, device = %config.device.display(), "Error opening device");
-                tokio::time::sleep(backoff).await;
-                backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
-                continue;
-            }
-        };
-        match device.grab() {
-            Ok(()) => break device,
-            Err(error) => {
-                tracing::error!(%error, device = %config.device.display(), "Error grabbing device");
-                tokio::time::sleep(backoff).await;
-                backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
-            }
-        }
-    };
+    let mut notify = SdNotify::new()?;
 
+    let device = open_device(Some(&notify), &config.device, true).await?;
     tracing::debug!(?device, "Opened and grabbed device");
+    notify
+        .status(&format!("Device {} opened", config.device.display()))
+        .await;
 
-    let mut dev = VirtualDevice::builder()?
-        .name(&config.name)
-        .input_id(InputId::new(
-            config.bus,
-            config.vendor_id,
-            config.product_id,
-            config.product_version,
-        ))
-        .with_properties(&AttributeSet::from_iter([PropType::POINTER]))?
-        .with_keys(&AttributeSet::from_iter((0..560).map(KeyCode)))?
-        .with_relative_axes(&AttributeSet::from_iter(
-            (if config.hi_res_enabled {
-                0..=12
-            } else {
-                0..=10
-            })
-            .map(RelativeAxisCode),
-        ))?;
-    for (axis, info) in device.get_absinfo()? {
-        dev = dev.with_absolute_axis(&UinputAbsSetup::new(axis, info))?;
-    }
-    if let Some(ff) = device.supported_ff() {
-        dev = dev.with_ff(ff)?;
-    }
-    if let Some(switch) = device.supported_switches() {
-        dev = dev.with_switches(switch)?;
-    }
-    let dev = dev.build()?;
+    let mut pointer_caps = Capabilities::new(&device, &config);
+    let dev = virtual_device(&device, &pointer_caps)?;
 
-    tracing::debug!(?dev, "Created virtual device");
+    let mut key_output = KeyOutput::new(&config.keys_name);
+    key_output.ensure(&virtual_keys(&device, &config, false))?;
 
     let mut udev_stream = dev.into_event_stream()?;
 
-    let socket = if let Some(path) = &config.socket_path {
-        if let Ok(true) = tokio::fs::try_exists(path).await {
-            tokio::fs::remove_file(path).await?;
+    // path of the socket we bound ourselves, hence have to clean up.
+    let mut socket_path = None;
+    let socket = if !config.socket_enabled {
+        None
+    } else if let Some(socket) = listen_socket()? {
+        Some(socket)
+    } else if let Some(path) = config.socket_path() {
+        if let Ok(true) = tokio::fs::try_exists(&path).await {
+            tokio::fs::remove_file(&path).await?;
+        }
+        tracing::debug!(path = %path.display(), "Binding control socket");
+        let socket = tokio::net::UnixListener::bind(&path)?;
+        socket_path = Some(path);
+        Some(socket)
+    } else {
+        tracing::warn!("Neither socket_path nor XDG_RUNTIME_DIR set, socket is disabled");
+        None
+    };
+
+    let mut persister = if config.persist.any() {
+        let persister = Persister::new();
+        if persister.is_none() {
+            tracing::warn!("Neither XDG_STATE_HOME nor HOME set, state won't be persisted");
         }
-        Some(tokio::net::UnixListener::bind(path)?)
+        persister
     } else {
         None
     };
 
+    let device_path = config.device.clone();
+    let keyboard_config = config.keyboard.clone();
     let mut controller = Controller::new(config);
+    if let Some(persister) = &mut persister {
+        controller.restore(persister.load());
+    }
 
+    let (command_tx, mut command_rx) = tokio::sync::mpsc::unbounded_channel();
     let state_vec_tx = if let Some(socket) = socket {
         let (state_vec_tx, state_vec_rx) = {
             let mut state = vec![];
-            controller.state_vec(&mut state);
+            controller.state().encode(&mut state);
             tokio::sync::watch::channel(state)
         };
-        tokio::spawn(handle_socket(socket, state_vec_rx));
+        tokio::spawn(handle_socket(socket, state_vec_rx, command_tx));
         Some(state_vec_tx)
     } else {
         None
     };
 
-    let mut stream = device.into_event_stream()?;
+    // `None` while the physical device is disconnected.
+    let mut stream = Some(device.into_event_stream()?);
+    let mut reopen = std::pin::pin!(Fuse::terminated());
+    // the keyboard is opened in the background, so the pointer works without
+    // it.
+    let mut keyboard = None;
+    let mut keyboard_open = std::pin::pin!(Fuse::terminated());
+    if let Some(config) = &keyboard_config {
+        keyboard_open.set(reopen_device(config.device.clone(), config.grab).fuse());
+    }
     let mut buf = vec![];
 
     tracing::debug!("Starting main loop");
 
-    SdNotify::new()?.ready().await?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    notify.ready().await;
 
     loop {
-        let mut ev = tokio::select! {
+        let input = tokio::select! {
             biased;
+            () = notify.watchdog() => continue,
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+            _ = sighup.recv() => {
+                tracing::info!(config = %cli.config.display(), "Reloading config");
+                notify.reloading().await;
+                let res = load_config(&cli.config).and_then(|config| {
+                    // otherwise applied once the device reconnects.
+                    if let Some(stream) = &stream {
+                        reload_devices(
+                            stream.device(),
+                            &config,
+                            &mut pointer_caps,
+                            &mut udev_stream,
+                            &mut key_output,
+                        )?;
+                    }
+                    Ok(config)
+                });
+                match res {
+                    Ok(config) => {
+                        controller.reload(config);
+                        notify.status("Config reloaded").await;
+                    }
+                    Err(error) => {
+                        tracing::error!(%error, "Failed to reload config, keeping the old one");
+                        notify.status(&format!("Config reload failed: {error}")).await;
+                    }
+                }
+                notify.ready().await;
+                continue;
+            }
             _ = controller.next_events(&mut buf) => {
                 tracing::trace!(?buf, "Controller emitted events");
-                udev_stream.device_mut().emit(&buf)?;
+                emit(udev_stream.device_mut(), &mut key_output, &buf)?;
                 buf.clear();
                 continue;
             }
             evt = udev_stream.next_event() => {
                 tracing::trace!(?evt, "Event virtual -> physical");
-                stream.device_mut().send_events(&[evt?])?;
+                let evt = evt?;
+                if let Some(stream) = &mut stream {
+                    stream.device_mut().send_events(&[evt])?;
+                }
                 continue;
             }
-            evt = stream.next_event() => {
+            evt = next_event(stream.as_mut()) => {
                 tracing::trace!(?evt, "Event physical -> virtual");
-                evt?
+                Input::Pointer(evt)
+            }
+            device = &mut reopen => Input::Reconnected(Box::new(device?)),
+            evt = next_keyboard_event(keyboard.as_mut()) => match evt {
+                Ok(KeyboardEvent::Physical(evt)) => {
+                    tracing::trace!(?evt, "Event keyboard -> virtual");
+                    Input::Keyboard(Ok(evt))
+                }
+                Ok(KeyboardEvent::Virtual(evt)) => {
+                    tracing::trace!(?evt, "Event virtual -> keyboard");
+                    let keyboard = keyboard.as_mut().expect("events only come from an existing keyboard");
+                    match keyboard.forward_led(evt) {
+                        Ok(()) => continue,
+                        Err(error) => Input::Keyboard(Err(error)),
+                    }
+                }
+                Err(error) => Input::Keyboard(Err(error)),
+            },
+            device = &mut keyboard_open => Input::KeyboardOpened(Box::new(device?)),
+            Some(command) = command_rx.recv() => {
+                tracing::debug!(?command, "Command received");
+                Input::Command(command)
+            }
+        };
+
+        match input {
+            Input::Pointer(ev) => {
+                let res = match (ev, &mut stream) {
+                    (Ok(ev), Some(stream)) => {
+                        handle_pointer_frame(&mut controller, stream, ev).await
+                    }
+                    (Ok(_), None) => unreachable!("no events without a device"),
+                    (Err(error), _) => Err(error),
+                };
+                match res {
+                    Err(error) if error.raw_os_error() == Some(libc::ENODEV) => {
+                        tracing::warn!(device = %device_path.display(), "Device disconnected");
+                        notify
+                            .status(&format!("Waiting for device {}", device_path.display()))
+                            .await;
+                        controller.disconnected(&mut buf);
+                        tracing::trace!(?buf, "Controller emitted events");
+                        emit(udev_stream.device_mut(), &mut key_output, &buf)?;
+                        buf.clear();
+                        stream = None;
+                        reopen.set(reopen_device(device_path.clone(), true).fuse());
+                    }
+                    res => res?,
+                }
+            }
+            Input::Reconnected(device) => {
+                tracing::info!(?device, "Device reconnected");
+                // it may be a different device by now, with a different
+                // identity and capabilities.
+                let config = controller.config();
+                pointer_caps = Capabilities::new(&device, config);
+                udev_stream = virtual_device(&device, &pointer_caps)?.into_event_stream()?;
+                key_output.ensure(&virtual_keys(&device, config, false))?;
+                stream = Some(device.into_event_stream()?);
+                notify
+                    .status(&format!("Device {} opened", device_path.display()))
+                    .await;
+            }
+            Input::Keyboard(ev) => {
+                let res = match (ev, &mut keyboard) {
+                    (Ok(ev), Some(keyboard)) => keyboard.handle_frame(&mut controller, ev).await,
+                    (Ok(_), None) => unreachable!("no events without a keyboard"),
+                    (Err(error), _) => Err(error),
+                };
+                if let Err(error) = res
+                    && let Some(config) = &keyboard_config
+                {
+                    if error.raw_os_error() == Some(libc::ENODEV) {
+                        tracing::warn!(device = %config.device.display(), "Keyboard disconnected");
+                    } else {
+                        tracing::error!(%error, device = %config.device.display(), "Error reading keyboard, reopening it");
+                    }
+                    if let Some(mut keyboard) = keyboard.take() {
+                        keyboard.ungrab();
+                    }
+                    keyboard_open.set(reopen_device(config.device.clone(), config.grab).fuse());
+                }
+            }
+            Input::KeyboardOpened(device) => {
+                let config = keyboard_config
+                    .as_ref()
+                    .expect("keyboard is only opened if configured");
+                match Keyboard::new(*device, config) {
+                    Ok(x) => keyboard = Some(x),
+                    Err(error) => {
+                        tracing::error!(%error, "Error setting up keyboard, it is disabled");
+                    }
+                }
+            }
+            Input::Command(command) => controller.command(command),
+        }
+
+        if let Some(state_vec_tx) = &state_vec_tx {
+            let mut state = Vec::new();
+            controller.state().encode(&mut state);
+            // movement frames mostly don't change anything clients see
+            state_vec_tx.send_if_modified(|x| {
+                let modified = *x != state;
+                if modified {
+                    *x = state;
+                }
+                modified
+            });
+        }
+        if let Some(persister) = &mut persister {
+            persister.save(controller.persisted_state());
+        }
+    }
+
+    tracing::info!("Shutting down");
+    notify.stopping().await;
+
+    controller.release_all(&mut buf);
+    tracing::trace!(?buf, "Controller emitted events");
+    if let Err(error) = emit(udev_stream.device_mut(), &mut key_output, &buf) {
+        tracing::error!(%error, "Error releasing pressed buttons");
+    }
+    if let Some(stream) = &mut stream
+        && let Err(error) = stream.device_mut().ungrab()
+    {
+        tracing::error!(%error, "Error ungrabbing device");
+    }
+    if let Some(keyboard) = &mut keyboard {
+        keyboard.ungrab();
+    }
+    if let Some(path) = &socket_path
+        && let Err(error) = tokio::fs::remove_file(path).await
+    {
+        tracing::error!(%error, path = %path.display(), "Error removing socket");
+    }
+    Ok(())
+}
+
+/// Identity, properties, keys and relative axes of the virtual pointer
+/// device, which can only change by recreating it.
+struct Capabilities {
+    name: String,
+    id: InputId,
+    properties: AttributeSet<PropType>,
+    keys: AttributeSet<KeyCode>,
+    axes: AttributeSet<RelativeAxisCode>,
+}
+
+impl Capabilities {
+    fn new(device: &Device, config: &Config) -> Self {
+        let (name, id) = config.identity(device);
+        Self {
+            name,
+            id,
+            properties: config.properties.iter().copied().collect(),
+            keys: virtual_keys(device, config, true),
+            axes: virtual_axes(device, config),
+        }
+    }
+}
+
+impl PartialEq for Capabilities {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.id == other.id
+            && self.properties.iter().eq(other.properties.iter())
+            && self.keys.iter().eq(other.keys.iter())
+            && self.axes.iter().eq(other.axes.iter())
+    }
+}
+
+/// Virtual pointer device mirroring the physical one.
+fn virtual_device(device: &Device, caps: &Capabilities) -> std::io::Result<VirtualDevice> {
+    let mut dev = VirtualDevice::builder()?
+        .name(&caps.name)
+        .input_id(caps.id.clone())
+        .with_properties(&caps.properties)?
+        .with_keys(&caps.keys)?
+        .with_relative_axes(&caps.axes)?;
+    for (axis, info) in device.get_absinfo()? {
+        dev = dev.with_absolute_axis(&UinputAbsSetup::new(axis, info))?;
+    }
+    if let Some(ff) = device.supported_ff() {
+        dev = dev.with_ff(ff)?;
+    }
+    if let Some(switch) = device.supported_switches() {
+        dev = dev.with_switches(switch)?;
+    }
+    let dev = dev.build()?;
+    tracing::debug!(?dev, "Created virtual device");
+    Ok(dev)
+}
+
+/// Keys of the physical device, and whatever the config may emit. Either the
+/// buttons for the pointer device, or the rest for the keyboard.
+fn virtual_keys(device: &Device, config: &Config, buttons: bool) -> AttributeSet<KeyCode> {
+    let physical = device.supported_keys().into_iter().flat_map(|x| x.iter());
+    let keys = physical
+        .chain(config.emitted_keys())
+        .filter(|x| is_button(*x) == buttons)
+        .collect::<AttributeSet<_>>();
+    tracing::debug!(?keys, buttons, "Virtual device keys");
+    keys
+}
+
+/// Apply a reloaded config to the virtual devices. On error, the old devices
+/// are kept.
+fn reload_devices(
+    device: &Device,
+    config: &Config,
+    pointer_caps: &mut Capabilities,
+    udev_stream: &mut VirtualEventStream,
+    key_output: &mut KeyOutput,
+) -> std::io::Result<()> {
+    let caps = Capabilities::new(device, config);
+    let pointer = if caps != *pointer_caps {
+        tracing::info!("Virtual device capabilities changed, recreating it");
+        Some(virtual_device(device, &caps)?.into_event_stream()?)
+    } else {
+        None
+    };
+    key_output.ensure(&virtual_keys(device, config, false))?;
+    if let Some(pointer) = pointer {
+        *udev_stream = pointer;
+        *pointer_caps = caps;
+    }
+    Ok(())
+}
+
+/// `BTN_*` codes, which belong on the pointer device.
+fn is_button(key: KeyCode) -> bool {
+    (KeyCode::BTN_0.0..=KeyCode::BTN_GEAR_UP.0).contains(&key.0)
+        || (KeyCode::BTN_DPAD_UP.0..=KeyCode::BTN_DPAD_RIGHT.0).contains(&key.0)
+        || (KeyCode::BTN_TRIGGER_HAPPY1.0..=KeyCode::BTN_TRIGGER_HAPPY40.0).contains(&key.0)
+}
+
+fn is_keyboard_event(ev: &InputEvent) -> bool {
+    ev.event_type() == EventType::KEY && !is_button(KeyCode(ev.code()))
+}
+
+/// Emit controller events, keyboard keys through the virtual keyboard and
+/// everything else through the pointer. Each switch between the devices ends
+/// a frame, so the order is kept.
+fn emit(
+    pointer: &mut VirtualDevice,
+    keyboard: &mut KeyOutput,
+    evts: &[InputEvent],
+) -> std::io::Result<()> {
+    for run in evts.chunk_by(|a, b| is_keyboard_event(a) == is_keyboard_event(b)) {
+        if is_keyboard_event(&run[0]) {
+            keyboard.emit(run)?;
+        } else {
+            pointer.emit(run)?;
+        }
+    }
+    Ok(())
+}
+
+/// Virtual keyboard for the `KEY_*` codes, so the pointer device isn't
+/// classified as a keyboard. Created once any such key may be emitted.
+struct KeyOutput {
+    name: String,
+    keys: AttributeSet<KeyCode>,
+    device: Option<VirtualDevice>,
+}
+
+impl KeyOutput {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            keys: AttributeSet::new(),
+            device: None,
+        }
+    }
+
+    /// Make sure the keys are advertised, recreating the device if needed.
+    fn ensure(&mut self, keys: &AttributeSet<KeyCode>) -> std::io::Result<()> {
+        if keys.iter().all(|x| self.keys.contains(x)) {
+            return Ok(());
+        }
+        let keys = self
+            .keys
+            .iter()
+            .chain(keys.iter())
+            .collect::<AttributeSet<_>>();
+        let dev = VirtualDevice::builder()?
+            .name(&self.name)
+            .with_keys(&keys)?
+            .build()?;
+        tracing::debug!(?dev, ?keys, "Created virtual keyboard for emitted keys");
+        // dropping the old device releases whatever it had pressed.
+        self.device = Some(dev);
+        self.keys = keys;
+        Ok(())
+    }
+
+    fn emit(&mut self, evts: &[InputEvent]) -> std::io::Result<()> {
+        let keys = evts.iter().map(|x| KeyCode(x.code())).collect();
+        self.ensure(&keys)?;
+        self.device
+            .as_mut()
+            .expect("ensured for non-empty events")
+            .emit(evts)
+    }
+}
+
+/// Relative axes of the physical device, and whatever the config may emit.
+fn virtual_axes(device: &Device, config: &Config) -> AttributeSet<RelativeAxisCode> {
+    let physical = device
+        .supported_relative_axes()
+        .into_iter()
+        .flat_map(|x| x.iter());
+    let hi_res = [
+        RelativeAxisCode::REL_WHEEL_HI_RES,
+        RelativeAxisCode::REL_HWHEEL_HI_RES,
+    ];
+    let axes = physical
+        .chain(config.emitted_axes())
+        .filter(|x| config.hi_res_enabled || !hi_res.contains(x))
+        .collect::<AttributeSet<_>>();
+    tracing::debug!(?axes, "Virtual device relative axes");
+    axes
+}
+
+fn load_config(path: &Path) -> anyhow::Result<Config> {
+    let mut config: Config = figment::Figment::new()
+        .join(figment::providers::Toml::file(path))
+        .extract()?;
+    config.validate()?;
+    config.assign_action_ids();
+    if let Some(dir) = path.parent() {
+        config.gesture.shapes = dir.join(&config.gesture.shapes);
+    }
+    Ok(config)
+}
+
+enum Input {
+    /// Errors included, to notice the device disconnecting.
+    Pointer(std::io::Result<InputEvent>),
+    Reconnected(Box<Device>),
+    /// Errors included, to notice the keyboard disconnecting.
+    Keyboard(std::io::Result<InputEvent>),
+    KeyboardOpened(Box<Device>),
+    Command(Command),
+}
+
+/// Wait for a device to (re)appear, in the background of the main loop.
+async fn reopen_device(path: PathBuf, grab: bool) -> std::io::Result<Device> {
+    open_device(None, &path, grab).await
+}
+
+async fn open_device(
+    notify: Option<&SdNotify>,
+    path: &Path,
+    grab: bool,
+) -> std::io::Result<Device> {
+    const MIN_BACKOFF: Duration = Duration::from_millis(1);
+    const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let mut device = match Device::open(path) {
+            Ok(x) => x,
+            Err(error) => {
+                tracing::error!(%error, device = %path.display(), "Error opening device");
+                if backoff == MIN_BACKOFF
+                    && let Some(notify) = notify
+                {
+                    notify
+                        .status(&format!("Waiting for device {}", path.display()))
+                        .await;
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
+                continue;
+            }
+        };
+        if !grab {
+            break Ok(device);
+        }
+        match device.grab() {
+            Ok(()) => break Ok(device),
+            Err(error) => {
+                tracing::error!(%error, device = %path.display(), "Error grabbing device");
+                tokio::time::sleep(backoff).await;
+                backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+async fn handle_pointer_frame(
+    controller: &mut Controller,
+    stream: &mut EventStream,
+    mut ev: InputEvent,
+) -> std::io::Result<()> {
+    let mut transaction = controller.start_transaction();
+    loop {
+        match ev.event_type() {
+            EventType::SYNCHRONIZATION if ev.code() == SynchronizationCode::SYN_REPORT.0 => {
+                break;
+            }
+            EventType::KEY => transaction.button(KeyCode(ev.code()), ev.value()),
+            EventType::RELATIVE => {
+                transaction.relative(RelativeAxisCode(ev.code()), ev.value(), ev.timestamp())
             }
+            EventType::MISC if ev.code() == MiscCode::MSC_SCAN.0 => {
+                tracing::trace!(?ev, "Filtered out MSC_SCAN event");
+            }
+            _ => transaction.passthrough(ev),
+        }
+        ev = stream.next_event().await?;
+        tracing::trace!(?ev, "Event physical -> virtual");
+    }
+    Ok(())
+}
+
+/// Secondary keyboard, whose selected keys drive tweakpoint.
+struct Keyboard {
+    stream: EventStream,
+    /// Virtual keyboard for keys we don't handle. Only exists if the physical
+    /// keyboard is grabbed.
+    passthrough: Option<LedKeyboard>,
+}
+
+impl Keyboard {
+    fn new(device: Device, config: &KeyboardConfig) -> std::io::Result<Self> {
+        tracing::debug!(?device, grab = config.grab, "Opened keyboard device");
+        let passthrough = if config.grab {
+            let dev = LedKeyboard::new(
+                &config.name,
+                device.input_id(),
+                device.supported_keys(),
+                device.supported_leds(),
+            )?;
+            tracing::debug!("Created virtual keyboard device");
+            Some(dev)
+        } else {
+            None
         };
+        Ok(Self {
+            stream: device.into_event_stream()?,
+            passthrough,
+        })
+    }
+
+    fn ungrab(&mut self) {
+        if self.passthrough.is_some()
+            && let Err(error) = self.stream.device_mut().ungrab()
+        {
+            tracing::error!(%error, "Error ungrabbing keyboard");
+        }
+    }
+
+    /// The grab keeps others from setting the LEDs, so pass on what they set
+    /// on the virtual keyboard.
+    fn forward_led(&mut self, ev: InputEvent) -> std::io::Result<()> {
+        if ev.event_type() == EventType::LED {
+            self.stream.device_mut().send_events(&[ev])?;
+        }
+        Ok(())
+    }
 
+    async fn handle_frame(
+        &mut self,
+        controller: &mut Controller,
+        mut ev: InputEvent,
+    ) -> std::io::Result<()> {
+        let mut passthrough = vec![];
         {
             let mut transaction = controller.start_transaction();
             loop {
@@ -205,32 +713,65 @@ async fn main() -> anyhow::Result<()> {
                     {
                         break;
                     }
-                    EventType::KEY => transaction.button(KeyCode(ev.code()), ev.value()),
-                    EventType::RELATIVE => {
-                        transaction.relative(RelativeAxisCode(ev.code()), ev.value())
-                    }
+                    EventType::KEY if transaction.keyboard(KeyCode(ev.code()), ev.value()) => {}
                     EventType::MISC if ev.code() == MiscCode::MSC_SCAN.0 => {
                         tracing::trace!(?ev, "Filtered out MSC_SCAN event");
                     }
-                    _ => transaction.passthrough(ev),
+                    _ => passthrough.push(ev),
                 }
-                ev = stream.next_event().await?;
-                tracing::trace!(?ev, "Event physical -> virtual");
+                ev = self.stream.next_event().await?;
+                tracing::trace!(?ev, "Event keyboard -> virtual");
             }
         }
-        if let Some(state_vec_tx) = &state_vec_tx {
-            state_vec_tx.send_modify(|x| {
-                x.clear();
-                controller.state_vec(x);
-            });
+        if let Some(dev) = &mut self.passthrough
+            && !passthrough.is_empty()
+        {
+            dev.emit(&passthrough)?;
         }
+        Ok(())
+    }
+}
+
+enum KeyboardEvent {
+    Physical(InputEvent),
+    /// Sent to the passthrough keyboard.
+    Virtual(InputEvent),
+}
+
+async fn next_keyboard_event(keyboard: Option<&mut Keyboard>) -> std::io::Result<KeyboardEvent> {
+    let Some(Keyboard {
+        stream,
+        passthrough,
+    }) = keyboard
+    else {
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        evt = stream.next_event() => evt.map(KeyboardEvent::Physical),
+        evt = async {
+            match passthrough {
+                Some(passthrough) => passthrough.next_event().await,
+                None => std::future::pending().await,
+            }
+        } => evt.map(KeyboardEvent::Virtual),
+    }
+}
+
+async fn next_event(stream: Option<&mut EventStream>) -> std::io::Result<InputEvent> {
+    match stream {
+        Some(stream) => stream.next_event().await,
+        None => std::future::pending().await,
     }
 }
 
 async fn handle_socket(
     socket: tokio::net::UnixListener,
     state_vec_rx: tokio::sync::watch::Receiver<Vec<u8>>,
+    command_tx: tokio::sync::mpsc::UnboundedSender<Command>,
 ) {
+    /// Commands are small, anything bigger is garbage.
+    const MAX_COMMAND_LEN: usize = 64 * 1024;
+
     use std::time::Instant;
     let mut limit = (Instant::now(), 0u8);
     loop {
@@ -253,6 +794,7 @@ async fn handle_socket(
             }
         };
         let mut state_vec_rx = state_vec_rx.clone();
+        let command_tx = command_tx.clone();
         tokio::spawn(async move {
             use tokio::io::AsyncReadExt;
             use tokio::io::AsyncWriteExt;
@@ -270,15 +812,37 @@ async fn handle_socket(
             };
             let reader = async {
                 loop {
-                    let mut buf = [0; 1024];
-                    match rx.read(&mut buf).await {
-                        Ok(0) => break, // client disconnect
-                        Ok(_) => {}     // ignore
+                    let mut len = [0; 4];
+                    match rx.read_exact(&mut len).await {
+                        Ok(_) => {}
+                        // client disconnect
+                        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
                         Err(e) => {
                             tracing::error!(error = %e, "Error reading from the socket");
                             break;
                         }
                     }
+                    let len = frame_len(len);
+                    if len > MAX_COMMAND_LEN {
+                        tracing::error!(len, "Command too long, disconnecting client");
+                        break;
+                    }
+                    let mut buf = vec![0; len];
+                    if let Err(e) = rx.read_exact(&mut buf).await {
+                        tracing::error!(error = %e, "Error reading from the socket");
+                        break;
+                    }
+                    match Command::decode(&buf) {
+                        Ok(command) => {
+                            if command_tx.send(command).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            tracing::error!(error = %e, "Invalid command, disconnecting client");
+                            break;
+                        }
+                    }
                 }
             };
             tokio::select! {
@@ -1,25 +1,82 @@
+use std::time::Duration;
+
 use tokio::net::UnixDatagram;
 
 pub struct SdNotify {
     sock: Option<UnixDatagram>,
+    watchdog: Option<tokio::time::Interval>,
 }
 
 impl SdNotify {
     pub fn new() -> std::io::Result<Self> {
         let Some(socket_path) = std::env::var_os("NOTIFY_SOCKET") else {
-            return Ok(Self { sock: None });
+            return Ok(Self {
+                sock: None,
+                watchdog: None,
+            });
         };
 
         let sock = tokio::net::UnixDatagram::unbound()?;
         sock.connect(socket_path)?;
-        Ok(Self { sock: Some(sock) })
+        Ok(Self {
+            sock: Some(sock),
+            watchdog: watchdog_interval().map(|period| {
+                tracing::debug!(?period, "Enabling systemd watchdog");
+                let mut interval = tokio::time::interval(period);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                interval
+            }),
+        })
     }
 
-    pub async fn ready(&self) -> std::io::Result<()> {
+    /// Notifications are best-effort: failures are logged, not returned, so
+    /// they never take the daemon down.
+    async fn send(&self, msg: &str) {
         let Some(sock) = &self.sock else {
-            return Ok(());
+            return;
         };
-        sock.send(b"READY=1").await?;
-        Ok(())
+        if let Err(error) = sock.send(msg.as_bytes()).await {
+            tracing::error!(%error, msg, "Error notifying service manager");
+        }
+    }
+
+    pub async fn ready(&self) {
+        self.send("READY=1").await
+    }
+
+    pub async fn reloading(&self) {
+        self.send("RELOADING=1").await
+    }
+
+    pub async fn stopping(&self) {
+        self.send("STOPPING=1").await
+    }
+
+    pub async fn status(&self, status: &str) {
+        tracing::debug!(%status, "Status");
+        self.send(&format!("STATUS={status}")).await
+    }
+
+    /// Wait for the next watchdog tick and ping the service manager. Never
+    /// completes if the watchdog isn't enabled.
+    pub async fn watchdog(&mut self) {
+        let Some(interval) = &mut self.watchdog else {
+            return std::future::pending().await;
+        };
+        interval.tick().await;
+        tracing::trace!("Watchdog ping");
+        self.send("WATCHDOG=1").await
+    }
+}
+
+/// Half of the watchdog timeout requested by systemd, as recommended by
+/// `sd_watchdog_enabled(3)`.
+fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = std::env::var_os("WATCHDOG_PID")
+        && pid.to_str()?.parse::<u32>().ok()? != std::process::id()
+    {
+        return None;
     }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
 }
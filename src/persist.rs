@@ -0,0 +1,84 @@
+//! Runtime state persisted across restarts.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Persisted fields. Fields not opted into persistence are `None`.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PersistedState {
+    pub scroll: Option<bool>,
+    pub slow: Option<f64>,
+    pub speed_level: Option<usize>,
+}
+
+pub struct Persister {
+    path: PathBuf,
+    last: PersistedState,
+}
+
+impl Persister {
+    /// `$XDG_STATE_HOME/tweakpoint/state.toml`, falling back to
+    /// `~/.local/state` if `XDG_STATE_HOME` isn't set.
+    pub fn new() -> Option<Self> {
+        let dir = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".local/state")))?;
+        Some(Self {
+            path: dir.join("tweakpoint").join("state.toml"),
+            last: PersistedState::default(),
+        })
+    }
+
+    pub fn load(&mut self) -> PersistedState {
+        let state = match std::fs::read_to_string(&self.path) {
+            Ok(data) => toml::from_str(&data).unwrap_or_else(|error| {
+                tracing::error!(%error, path = %self.path.display(), "Invalid persisted state, ignoring");
+                PersistedState::default()
+            }),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                PersistedState::default()
+            }
+            Err(error) => {
+                tracing::error!(%error, path = %self.path.display(), "Error reading persisted state");
+                PersistedState::default()
+            }
+        };
+        tracing::debug!(?state, "Loaded persisted state");
+        self.last = state.clone();
+        state
+    }
+
+    /// Write the state if it changed since the last write.
+    pub fn save(&mut self, state: PersistedState) {
+        if state == self.last {
+            return;
+        }
+        tracing::debug!(?state, "Persisting state");
+        if let Err(error) = self.write(&state) {
+            tracing::error!(%error, path = %self.path.display(), "Error persisting state");
+        }
+        // don't retry on every event if writing fails.
+        self.last = state;
+    }
+
+    fn write(&self, state: &PersistedState) -> anyhow::Result<()> {
+        Ok(write_file(&self.path, toml::to_string(state)?)?)
+    }
+}
+
+/// Write `data` to a temp file next to `path` and rename it over `path`, so
+/// the file is never seen half-written and a crash keeps the old contents.
+pub fn write_file(path: &Path, data: impl AsRef<[u8]>) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)
+}
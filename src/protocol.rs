@@ -0,0 +1,421 @@
+//! State socket protocol.
+//!
+//! Every message in either direction is a frame: a little-endian `u32` payload
+//! length followed by the payload. The daemon sends a [`State`] frame on
+//! connect and after every change, clients may send [`Command`] frames.
+//!
+//! New fields are only ever appended to the end of a payload, and decoders
+//! ignore trailing data, so older clients keep working with newer daemons.
+
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind, Read, Result},
+};
+
+use evdev::KeyCode;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GestureDir {
+    U = b'U',
+    D = b'D',
+    L = b'L',
+    R = b'R',
+}
+
+impl GestureDir {
+    fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            b'U' => Self::U,
+            b'D' => Self::D,
+            b'L' => Self::L,
+            b'R' => Self::R,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LockStep {
+    /// Button is ostensibly released both physically and logically.
+    Released = b'R',
+    /// Button is logically held but physically released
+    Locked = b'L',
+    /// Button is both logically and physically held, but will be released on
+    /// physical release.
+    WillRelease = b'W',
+    /// Button was released automatically by a lock policy, and is now
+    /// released both physically and logically.
+    AutoReleased = b'A',
+}
+
+impl LockStep {
+    fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            b'R' => Self::Released,
+            b'L' => Self::Locked,
+            b'W' => Self::WillRelease,
+            b'A' => Self::AutoReleased,
+            _ => return None,
+        })
+    }
+}
+
+/// Outcome of a finished gesture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GestureResult {
+    /// Matched and ran an action.
+    Executed = b'E',
+    /// Finished without matching any action.
+    Unmatched = b'U',
+    /// Aborted before release.
+    Cancelled = b'C',
+}
+
+impl GestureResult {
+    fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            b'E' => Self::Executed,
+            b'U' => Self::Unmatched,
+            b'C' => Self::Cancelled,
+            _ => return None,
+        })
+    }
+}
+
+/// Daemon state, as published on the socket.
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    pub scroll_active: bool,
+    /// Buttons managed by the button lock, if it's engaged.
+    pub buttons: BTreeMap<KeyCode, LockStep>,
+    /// Gesture in progress.
+    pub gesture: Vec<GestureDir>,
+    /// Pointer speed factor.
+    pub slow: f64,
+    /// Index into the configured speed levels, if the speed was set from there.
+    pub speed_level: Option<usize>,
+    /// Outcome of the last gesture, if there was one.
+    pub last_gesture: Option<GestureResult>,
+    /// Movement trail of the gesture in progress, centered and scaled to fit
+    /// the unit square.
+    pub trail: Vec<(f32, f32)>,
+    /// Gesture keys still reachable from the strokes so far.
+    pub candidates: Vec<GestureCandidate>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GestureCandidate {
+    pub key: String,
+    /// Action the key triggers, written the same way as in the config file.
+    pub action: String,
+}
+
+impl State {
+    /// Append a frame with the encoded state to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        frame(out, |out| {
+            out.push(if self.scroll_active { 0x01 } else { 0x00 });
+            frame(out, |out| {
+                for (lock_btn, lock_step) in &self.buttons {
+                    out.extend_from_slice(&lock_btn.0.to_le_bytes());
+                    out.push(*lock_step as u8);
+                }
+            });
+            frame(out, |out| {
+                for dir in &self.gesture {
+                    out.push(*dir as u8);
+                }
+            });
+            out.extend_from_slice(&self.slow.to_le_bytes());
+            let speed_level = self.speed_level.map_or(u32::MAX, |x| x as u32);
+            out.extend_from_slice(&speed_level.to_le_bytes());
+            out.push(self.last_gesture.map_or(0, |x| x as u8));
+            frame(out, |out| {
+                for (x, y) in &self.trail {
+                    out.extend_from_slice(&x.to_le_bytes());
+                    out.extend_from_slice(&y.to_le_bytes());
+                }
+            });
+            frame(out, |out| {
+                for candidate in &self.candidates {
+                    frame(out, |out| out.extend_from_slice(candidate.key.as_bytes()));
+                    frame(out, |out| {
+                        out.extend_from_slice(candidate.action.as_bytes())
+                    });
+                }
+            });
+        });
+    }
+
+    /// Decode a state frame payload, i.e. without the length prefix.
+    pub fn decode(mut ptr: &[u8]) -> Result<Self> {
+        let ptr = &mut ptr;
+        let scroll_active = read_array::<1>(ptr)?[0] != 0;
+        let buttons = with_len(ptr, |ptr| {
+            let mut buttons = BTreeMap::new();
+            while !ptr.is_empty() {
+                let key_code = KeyCode::new(u16::from_le_bytes(read_array(ptr)?));
+                let [step] = read_array(ptr)?;
+                match LockStep::from_byte(step) {
+                    Some(step) => {
+                        buttons.insert(key_code, step);
+                    }
+                    None => tracing::warn!(?key_code, step, "Unexpected button state"),
+                }
+            }
+            Ok(buttons)
+        })?;
+        let gesture = with_len(ptr, |ptr| {
+            let mut gesture = Vec::new();
+            while !ptr.is_empty() {
+                let [byte] = read_array(ptr)?;
+                match GestureDir::from_byte(byte) {
+                    Some(dir) => gesture.push(dir),
+                    None => tracing::warn!(byte, "Unexpected gesture direction"),
+                }
+            }
+            Ok(gesture)
+        })?;
+        let slow = f64::from_le_bytes(read_array(ptr)?);
+        let speed_level = optional(ptr, |ptr| {
+            let level = u32::from_le_bytes(read_array(ptr)?);
+            Ok((level != u32::MAX).then_some(level as usize))
+        })?
+        .flatten();
+        let last_gesture = optional(ptr, |ptr| {
+            let [byte] = read_array(ptr)?;
+            Ok(GestureResult::from_byte(byte))
+        })?
+        .flatten();
+        let trail = optional(ptr, |ptr| {
+            with_len(ptr, |ptr| {
+                let mut trail = Vec::new();
+                while !ptr.is_empty() {
+                    let x = f32::from_le_bytes(read_array(ptr)?);
+                    let y = f32::from_le_bytes(read_array(ptr)?);
+                    trail.push((x, y));
+                }
+                Ok(trail)
+            })
+        })?
+        .unwrap_or_default();
+        let candidates = optional(ptr, |ptr| {
+            with_len(ptr, |ptr| {
+                let mut candidates = Vec::new();
+                while !ptr.is_empty() {
+                    let key = with_len(ptr, read_string)?;
+                    let action = with_len(ptr, read_string)?;
+                    candidates.push(GestureCandidate { key, action });
+                }
+                Ok(candidates)
+            })
+        })?
+        .unwrap_or_default();
+        Ok(Self {
+            scroll_active,
+            buttons,
+            gesture,
+            slow,
+            speed_level,
+            last_gesture,
+            trail,
+            candidates,
+        })
+    }
+}
+
+/// Command sent from a client to the daemon.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Run an action, written the same way as in the config file, e.g.
+    /// `"ToggleScroll"` or `{ ToggleSlow = 0.5 }`. The action is pressed and
+    /// immediately released. It can only emit buttons the virtual pointer
+    /// advertises, i.e. those of the device or mentioned in the config; others
+    /// are dropped.
+    Run(String),
+}
+
+impl Command {
+    const RUN: u8 = b'A';
+
+    /// Append a frame with the encoded command to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        frame(out, |out| match self {
+            Command::Run(action) => {
+                out.push(Self::RUN);
+                out.extend_from_slice(action.as_bytes());
+            }
+        });
+    }
+
+    /// Decode a command frame payload, i.e. without the length prefix.
+    pub fn decode(mut ptr: &[u8]) -> Result<Self> {
+        let [tag] = read_array(&mut ptr)?;
+        match tag {
+            Self::RUN => Ok(Command::Run(read_string(&mut ptr)?)),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown command {tag:#04x}"),
+            )),
+        }
+    }
+}
+
+/// Read a frame length prefix.
+pub fn frame_len(prefix: [u8; 4]) -> usize {
+    u32::from_le_bytes(prefix) as usize
+}
+
+fn frame(out: &mut Vec<u8>, action: impl FnOnce(&mut Vec<u8>)) {
+    out.extend_from_slice(&[0x00; 4]);
+    let pos = out.len();
+    action(out);
+    let len = (out.len() - pos) as u32;
+    out[pos - 4..pos].copy_from_slice(&len.to_le_bytes());
+}
+
+fn with_len<R>(ptr: &mut &[u8], action: impl FnOnce(&mut &[u8]) -> Result<R>) -> Result<R> {
+    let len = frame_len(read_array(ptr)?);
+    if len > ptr.len() {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    let (mut cur, rest) = ptr.split_at(len);
+    *ptr = rest;
+    action(&mut cur)
+}
+
+/// Read a field that older daemons don't send.
+fn optional<R>(ptr: &mut &[u8], action: impl FnOnce(&mut &[u8]) -> Result<R>) -> Result<Option<R>> {
+    if ptr.is_empty() {
+        Ok(None)
+    } else {
+        action(ptr).map(Some)
+    }
+}
+
+/// Read the rest of `ptr` as a string.
+fn read_string(ptr: &mut &[u8]) -> Result<String> {
+    let s = String::from_utf8(ptr.to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    *ptr = &[];
+    Ok(s)
+}
+
+fn read_array<const N: usize>(ptr: &mut &[u8]) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    ptr.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        State {
+            scroll_active: true,
+            buttons: BTreeMap::from([
+                (KeyCode::BTN_LEFT, LockStep::Locked),
+                (KeyCode::BTN_RIGHT, LockStep::AutoReleased),
+            ]),
+            gesture: vec![GestureDir::U, GestureDir::L],
+            slow: 0.5,
+            speed_level: Some(2),
+            last_gesture: Some(GestureResult::Cancelled),
+            trail: vec![(-0.5, 0.25), (0.5, -0.25)],
+            candidates: vec![GestureCandidate {
+                key: "UL+".to_owned(),
+                action: "\"ToggleScroll\"".to_owned(),
+            }],
+        }
+    }
+
+    /// Check the length prefix of a single frame, and strip it.
+    fn payload(frame: &[u8]) -> &[u8] {
+        let len = frame_len(frame[..4].try_into().unwrap());
+        assert_eq!(len, frame.len() - 4);
+        &frame[4..]
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let empty = State {
+            scroll_active: false,
+            buttons: BTreeMap::new(),
+            gesture: vec![],
+            slow: 1.0,
+            speed_level: None,
+            last_gesture: None,
+            trail: vec![],
+            candidates: vec![],
+        };
+        for state in [state(), empty] {
+            let mut out = vec![];
+            state.encode(&mut out);
+            assert_eq!(State::decode(payload(&out)).unwrap(), state);
+        }
+    }
+
+    #[test]
+    fn state_ignores_trailing_data() {
+        let mut out = vec![];
+        state().encode(&mut out);
+        let mut payload = payload(&out).to_vec();
+        payload.extend_from_slice(b"from the future");
+        assert_eq!(State::decode(&payload).unwrap(), state());
+    }
+
+    #[test]
+    fn state_from_older_daemons() {
+        let state = state();
+        let mut out = vec![];
+        state.encode(&mut out);
+        let payload = payload(&out);
+
+        // where each older version of the payload ended
+        let slow_end = 1 + (4 + 3 * state.buttons.len()) + (4 + state.gesture.len()) + 8;
+        let speed_level_end = slow_end + 4;
+        let last_gesture_end = speed_level_end + 1;
+        let trail_end = last_gesture_end + 4 + 8 * state.trail.len();
+
+        let mut expected = State {
+            candidates: vec![],
+            ..state.clone()
+        };
+        assert_eq!(State::decode(&payload[..trail_end]).unwrap(), expected);
+        expected.trail = vec![];
+        assert_eq!(
+            State::decode(&payload[..last_gesture_end]).unwrap(),
+            expected
+        );
+        expected.last_gesture = None;
+        assert_eq!(
+            State::decode(&payload[..speed_level_end]).unwrap(),
+            expected
+        );
+        expected.speed_level = None;
+        assert_eq!(State::decode(&payload[..slow_end]).unwrap(), expected);
+        assert!(State::decode(&payload[..slow_end - 1]).is_err());
+    }
+
+    #[test]
+    fn command_round_trip() {
+        for command in [
+            Command::Run("\"ToggleScroll\"".to_owned()),
+            Command::Run("{ ToggleSlow = 0.5 }".to_owned()),
+            Command::Run(String::new()),
+        ] {
+            let mut out = vec![];
+            command.encode(&mut out);
+            assert_eq!(Command::decode(payload(&out)).unwrap(), command);
+        }
+    }
+
+    #[test]
+    fn command_unknown_tag() {
+        assert!(Command::decode(b"?").is_err());
+        assert!(Command::decode(b"").is_err());
+    }
+}
@@ -1,13 +1,19 @@
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
     pin::Pin,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
 use evdev::{EventType, InputEvent, KeyCode, RelativeAxisCode};
+use tweakpoint::protocol::{GestureCandidate, GestureDir, GestureResult, LockStep};
 
 use crate::{
-    config::{Action, Direction, Gestures, MetaConfig},
+    config::{
+        Action, Autofire, AxisTrigger, ButtonMapping, ContinuousGesture, Direction, GestureConfig,
+        GestureStep, Gestures, LockConfig, MetaConfig, ScrollAcceleration, Sequence,
+        SequenceConflict, SequenceStep,
+    },
+    gesture::{self, Length, Point, Shapes, Speed, Stroke},
     utils::{EitherIter, IteratorExt},
 };
 
@@ -16,46 +22,194 @@ pub struct State {
     pub meta_down: MetaDown,
     pub scroll: ScrollState,
     pub slow: Option<f64>,
+    pub speed: SpeedState,
     pub lock: LockState,
-    pub gesture_dir: Option<Vec<GestureDir>>,
+    pub click_lock: ClickLockState,
+    pub gesture: Option<GestureState>,
+    pub gesture_config: GestureConfig,
+    pub last_gesture: Option<GestureResult>,
+    pub shapes: Shapes,
+    pub continuous_gesture: Option<ContinuousGestureState>,
+    pub sequence: SequenceState,
+    pub autofire: AutofireState,
+    pub layers: LayerState,
+    /// Physical buttons currently held, with what they were resolved to.
+    pub pressed_buttons: BTreeMap<KeyCode, PressedButton>,
+    pub axis_triggers: AxisTriggerState,
+    /// `Toggle` actions, so they keep alternating when run from the socket
+    /// and across reloads.
+    pub toggles: HashMap<ActionKey, ToggleState>,
+    /// Branch taken by `If` actions on press, so that release goes to the same
+    /// one.
+    pub conditionals: HashMap<ActionKey, bool>,
 }
 
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum GestureDir {
-    U = b'U',
-    D = b'D',
-    L = b'L',
-    R = b'R',
+/// What the state of `If` and `Toggle` actions is kept by.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ActionKey {
+    /// Assigned by [`crate::config::Config::assign_action_ids`].
+    Id(usize),
+    /// Actions from the socket, which have no id.
+    Description(String),
+}
+
+#[derive(Default)]
+pub struct ToggleState {
+    /// Whether the last press ran `on`, i.e. the next one runs `off`.
+    pub is_on: bool,
+    /// Branch run on the last press, for its release.
+    pub pressed_on: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct PressedButton {
+    pub mapping: Option<ButtonMapping>,
+    pub mapped_key: KeyCode,
+    pub chord: Option<Action>,
+    /// Whether the press activated the chord.
+    pub chorded: bool,
+    pub pressed_at: Instant,
+}
+
+impl PressedButton {
+    /// A press that does nothing, and neither does its release.
+    pub fn swallowed(key_code: KeyCode) -> Self {
+        Self {
+            mapping: Some(ButtonMapping::Action(Action::None)),
+            mapped_key: key_code,
+            chord: None,
+            chorded: false,
+            pressed_at: Instant::now(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct AxisTriggerState {
+    axes: HashMap<RelativeAxisCode, AxisHold>,
+}
+
+struct AxisHold {
+    positive: bool,
+    /// Counts towards the next threshold.
+    counts: u32,
+    last: SystemTime,
+    fired: bool,
+}
+
+impl AxisTriggerState {
+    /// Accumulate movement, returning how many times to run the action.
+    pub fn accumulate(
+        &mut self,
+        axis: RelativeAxisCode,
+        value: i32,
+        time: SystemTime,
+        trigger: &AxisTrigger,
+    ) -> u32 {
+        let positive = value > 0;
+        let hold = self.axes.entry(axis).or_insert(AxisHold {
+            positive,
+            counts: 0,
+            last: time,
+            fired: false,
+        });
+        let timed_out = time
+            .duration_since(hold.last)
+            .is_ok_and(|x| x > trigger.hold_timeout);
+        if hold.positive != positive || timed_out {
+            // direction changed or a new hold
+            hold.positive = positive;
+            hold.counts = 0;
+            hold.fired = false;
+        }
+        hold.last = time;
+        hold.counts = hold.counts.saturating_add(value.unsigned_abs());
+        let threshold = trigger.threshold.get();
+        let crossed = hold.counts / threshold;
+        hold.counts %= threshold;
+        if crossed == 0 || (hold.fired && !trigger.repeat) {
+            return 0;
+        }
+        hold.fired = true;
+        if trigger.repeat { crossed } else { 1 }
+    }
+}
+
+#[derive(Default)]
+pub struct LayerState {
+    /// Active layers, the last one is on top, and whether they're held rather
+    /// than toggled on.
+    active: Vec<(String, bool)>,
+    /// Layer active for the next button press only.
+    one_shot: Option<String>,
+}
+
+impl LayerState {
+    /// Active layer names, top to bottom.
+    pub fn active(&self) -> impl Iterator<Item = &str> + Clone {
+        self.one_shot
+            .iter()
+            .chain(self.active.iter().rev().map(|(name, _)| name))
+            .map(String::as_str)
+    }
+
+    pub fn activate(&mut self, name: &str, held: bool) {
+        tracing::debug!(layer = %name, held, "Layer activated");
+        self.active.push((name.to_owned(), held));
+    }
+
+    pub fn deactivate(&mut self, name: &str) {
+        if let Some(pos) = self.active.iter().rposition(|(x, held)| x == name && *held) {
+            tracing::debug!(layer = %name, "Layer deactivated");
+            self.active.remove(pos);
+        }
+    }
+
+    pub fn toggle(&mut self, name: &str) {
+        if self.active.iter().any(|(x, _)| x == name) {
+            tracing::debug!(layer = %name, "Layer toggled off");
+            self.active.retain(|(x, _)| x != name);
+        } else {
+            self.activate(name, false);
+        }
+    }
+
+    /// Drop layers whose buttons are no longer held, and the one-shot layer.
+    pub fn release_held(&mut self) {
+        self.active.retain(|(_, held)| !held);
+        self.one_shot = None;
+    }
+
+    pub fn set_one_shot(&mut self, name: &str) {
+        tracing::debug!(layer = %name, "One-shot layer armed");
+        self.one_shot = Some(name.to_owned());
+    }
+
+    pub fn take_one_shot(&mut self) -> Option<String> {
+        self.one_shot.take()
+    }
 }
 
 #[derive(Default)]
 pub struct LockState {
     btn_states: BTreeMap<KeyCode, LockStep>,
+    /// Set while the lock is engaged.
+    config: Option<LockConfig>,
+    idle_deadline: Option<tokio::time::Instant>,
+    stop_deadline: Option<tokio::time::Instant>,
 }
 
-#[derive(Clone, Copy, Debug)]
-#[repr(u8)]
-pub enum LockStep {
-    /// Button is ostensibly released both physically and logically.
-    Released = b'R',
-    /// Button is logically held but physically released
-    Locked = b'L',
-    /// Button is both logically and physically held, but will be released on
-    /// physical release.
-    WillRelease = b'W',
-}
-
-impl LockStep {
-    fn step(&mut self, value: i32) -> bool {
-        *self = match *self {
-            LockStep::Released if value == 0 => LockStep::Locked,
-            LockStep::Locked if value == 1 => LockStep::WillRelease,
-            LockStep::WillRelease if value == 0 => LockStep::Released,
-            x => x,
-        };
-        matches!(self, LockStep::Released)
-    }
+/// Advance the lock state machine on a physical button event. Returns `true`
+/// if the event should be passed through.
+fn lock_step(step: &mut LockStep, value: i32) -> bool {
+    *step = match *step {
+        LockStep::Released | LockStep::AutoReleased if value == 0 => LockStep::Locked,
+        LockStep::AutoReleased if value == 1 => LockStep::Released,
+        LockStep::Locked if value == 1 => LockStep::WillRelease,
+        LockStep::WillRelease if value == 0 => LockStep::Released,
+        x => x,
+    };
+    matches!(step, LockStep::Released | LockStep::AutoReleased)
 }
 
 impl LockState {
@@ -63,13 +217,14 @@ impl LockState {
         self.btn_states.iter().map(|(k, v)| (*k, *v))
     }
 
-    pub fn toggle(
-        &mut self,
-        btns: &BTreeSet<KeyCode>,
-    ) -> impl IntoIterator<Item = InputEvent> + use<> {
-        if self.btn_states.is_empty() {
+    pub fn toggle(&mut self, config: &LockConfig) -> impl IntoIterator<Item = InputEvent> + use<> {
+        let btns = &config.buttons;
+        if self.config.is_none() {
             tracing::debug!(state = "on", ?btns, "Toggle lock state");
             self.btn_states = btns.iter().map(|x| (*x, LockStep::Released)).collect();
+            self.config = Some(config.clone());
+            self.idle_deadline = None;
+            self.stop_deadline = None;
             vec![]
         } else {
             tracing::debug!(state = "off", ?btns, "Toggle lock state");
@@ -87,6 +242,7 @@ impl LockState {
                 })
                 .collect();
             self.btn_states.clear();
+            self.config = None;
             res
         }
     }
@@ -94,13 +250,213 @@ impl LockState {
     pub fn check(&mut self, button: &KeyCode, value: i32) -> Option<KeyCode> {
         if let Some(entry) = self.btn_states.get_mut(button) {
             // "lock" just filters out consecutive {0, 1} sequences.
-            if !entry.step(value) {
+            let pass = lock_step(entry, value);
+            let entry = *entry;
+            if matches!(entry, LockStep::Locked) {
+                self.arm_idle();
+            }
+            if !pass {
                 tracing::debug!(?entry, ?button, "Locking button");
                 return None;
             }
         }
         Some(*button)
     }
+
+    pub fn is_engaged(&self) -> bool {
+        self.config.is_some()
+    }
+
+    fn any_locked(&self) -> bool {
+        self.btn_states
+            .values()
+            .any(|x| matches!(x, LockStep::Locked))
+    }
+
+    fn arm_idle(&mut self) {
+        if let Some(timeout) = self.config.as_ref().and_then(|x| x.idle_timeout) {
+            self.idle_deadline = Some(tokio::time::Instant::now() + timeout);
+        }
+    }
+
+    /// Release all locked buttons, marking them as automatically released.
+    fn auto_release(&mut self, reason: &str) -> Vec<InputEvent> {
+        self.idle_deadline = None;
+        self.stop_deadline = None;
+        self.btn_states
+            .iter_mut()
+            .filter(|(_, step)| matches!(step, LockStep::Locked))
+            .map(|(key, step)| {
+                tracing::debug!(?key, %reason, "Automatically releasing locked key");
+                *step = LockStep::AutoReleased;
+                InputEvent::new(EventType::KEY.0, key.0, 0)
+            })
+            .collect()
+    }
+
+    /// Release locked buttons, as their physical buttons were released.
+    pub fn release_locked(&mut self) -> Vec<InputEvent> {
+        self.auto_release("device disconnected")
+    }
+
+    /// Notify about a button event, other than the meta key or chords.
+    pub fn on_button(&mut self, button: &KeyCode, value: i32) -> Vec<InputEvent> {
+        let Some(config) = &self.config else {
+            return vec![];
+        };
+        if value == 1
+            && config.release_on_other_button
+            && !self.btn_states.contains_key(button)
+            && self.any_locked()
+        {
+            return self.auto_release("other button pressed");
+        }
+        if self.idle_deadline.is_some() {
+            self.arm_idle();
+        }
+        vec![]
+    }
+
+    /// Notify about pointer movement.
+    pub fn on_move(&mut self) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        let stop_timeout = config.release_on_stop;
+        if !self.any_locked() {
+            return;
+        }
+        if let Some(timeout) = stop_timeout {
+            self.stop_deadline = Some(tokio::time::Instant::now() + timeout);
+        }
+        if self.idle_deadline.is_some() {
+            self.arm_idle();
+        }
+    }
+
+    pub async fn wait(&mut self) {
+        match self
+            .idle_deadline
+            .into_iter()
+            .chain(self.stop_deadline)
+            .min()
+        {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Release locked buttons if a timeout expired.
+    pub fn tick(&mut self) -> Vec<InputEvent> {
+        let now = tokio::time::Instant::now();
+        if self.idle_deadline.is_some_and(|x| x <= now) {
+            self.auto_release("idle timeout")
+        } else if self.stop_deadline.is_some_and(|x| x <= now) {
+            self.auto_release("pointer stopped")
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// Buttons that latch when held for long enough.
+#[derive(Default)]
+pub struct ClickLockState {
+    btn_states: BTreeMap<KeyCode, ClickLockEntry>,
+}
+
+struct ClickLockEntry {
+    step: LockStep,
+    hold_time: Duration,
+    /// Set while the button is physically held, but not latched yet.
+    deadline: Option<tokio::time::Instant>,
+    latched: bool,
+}
+
+impl ClickLockState {
+    pub fn configure(&mut self, config: &BTreeMap<KeyCode, humantime_serde::Serde<Duration>>) {
+        self.btn_states = config
+            .iter()
+            .map(|(key, hold_time)| {
+                (
+                    *key,
+                    ClickLockEntry {
+                        step: LockStep::Released,
+                        hold_time: **hold_time,
+                        deadline: None,
+                        latched: false,
+                    },
+                )
+            })
+            .collect();
+    }
+
+    /// Latched buttons.
+    pub fn state_vec(&self) -> impl Iterator<Item = (KeyCode, LockStep)> {
+        self.btn_states
+            .iter()
+            .filter(|(_, v)| !matches!(v.step, LockStep::Released))
+            .map(|(k, v)| (*k, v.step))
+    }
+
+    pub fn check(&mut self, button: &KeyCode, value: i32) -> Option<KeyCode> {
+        let Some(entry) = self.btn_states.get_mut(button) else {
+            return Some(*button);
+        };
+        match (entry.step, value) {
+            (LockStep::Released, 1) => {
+                entry.deadline = Some(tokio::time::Instant::now() + entry.hold_time);
+                Some(*button)
+            }
+            (LockStep::Released, 0) if !entry.latched => {
+                // short press, regular click
+                entry.deadline = None;
+                Some(*button)
+            }
+            _ => {
+                entry.latched = false;
+                let pass = lock_step(&mut entry.step, value);
+                tracing::debug!(step = ?entry.step, ?button, "ClickLock button");
+                pass.then_some(*button)
+            }
+        }
+    }
+
+    /// Release latched buttons and forget held ones.
+    pub fn reset(&mut self) -> Vec<InputEvent> {
+        self.btn_states
+            .iter_mut()
+            .filter_map(|(key, entry)| {
+                let locked = matches!(entry.step, LockStep::Locked);
+                entry.step = LockStep::Released;
+                entry.deadline = None;
+                entry.latched = false;
+                locked.then(|| {
+                    tracing::debug!(?key, "Releasing latched key");
+                    InputEvent::new(EventType::KEY.0, key.0, 0)
+                })
+            })
+            .collect()
+    }
+
+    pub async fn wait(&mut self) {
+        match self.btn_states.values().filter_map(|x| x.deadline).min() {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Latch buttons held for long enough.
+    pub fn tick(&mut self) {
+        let now = tokio::time::Instant::now();
+        for (button, entry) in &mut self.btn_states {
+            if entry.deadline.is_some_and(|x| x <= now) {
+                tracing::debug!(?button, "ClickLock latched");
+                entry.deadline = None;
+                entry.latched = true;
+            }
+        }
+    }
 }
 
 #[derive(Default)]
@@ -183,6 +539,25 @@ impl MetaDown {
 }
 
 impl State {
+    /// Forget everything tied to physically held buttons, once the device is
+    /// gone. Returns the releases for buttons this held.
+    pub fn reset_input(&mut self) -> Vec<InputEvent> {
+        let mut evts = self.sequence.cancel();
+        evts.extend(self.autofire.stop_all());
+        evts.extend(self.lock.release_locked());
+        evts.extend(self.click_lock.reset());
+        self.meta_down.reset();
+        self.scroll.release_all();
+        self.layers.release_held();
+        self.pressed_buttons.clear();
+        self.axis_triggers = AxisTriggerState::default();
+        self.continuous_gesture = None;
+        if self.gesture_active() {
+            self.cancel_gesture("device disconnected");
+        }
+        evts
+    }
+
     pub fn handle_meta_up(
         &mut self,
         config: &MetaConfig,
@@ -216,8 +591,146 @@ impl State {
         evt
     }
 
-    pub fn start_gesture(&mut self) -> impl IntoIterator<Item = InputEvent> + use<> {
-        self.gesture_dir = Some(vec![]);
+    pub fn gesture_active(&self) -> bool {
+        self.gesture.as_ref().is_some_and(|x| !x.cancelled) || self.continuous_gesture.is_some()
+    }
+
+    pub fn cancel_gesture(&mut self, reason: &str) {
+        if let Some(gesture) = &mut self.gesture
+            && !gesture.cancelled
+        {
+            tracing::debug!(reason, dirs = ?gesture.dirs(), "Gesture cancelled");
+            gesture.cancelled = true;
+            self.last_gesture = Some(GestureResult::Cancelled);
+        }
+    }
+
+    /// Feed movement into the gesture in progress. Only `significant`
+    /// movement counts as a stroke.
+    pub fn gesture_move(&mut self, movement: (i32, i32), significant: bool) {
+        let Some(gesture) = &mut self.gesture else {
+            return;
+        };
+        if gesture.cancelled || movement == (0, 0) {
+            return;
+        }
+        let config = &self.gesture_config;
+        gesture.pos.0 += i64::from(movement.0);
+        gesture.pos.1 += i64::from(movement.1);
+        if gesture.trail.len() >= MAX_TRAIL_POINTS {
+            // halve the resolution rather than growing without bound
+            gesture.trail = gesture.trail.iter().copied().step_by(2).collect();
+        }
+        gesture
+            .trail
+            .push((gesture.pos.0 as f64, gesture.pos.1 as f64));
+        gesture.published_trail = gesture::normalize(&gesture.trail, false)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(x, y)| (x as f32, y as f32))
+            .collect();
+        let now = Instant::now();
+        let dir = significant.then(|| {
+            if movement.0.abs() > movement.1.abs() {
+                // X axis
+                if movement.0 > 0 {
+                    GestureDir::R
+                } else {
+                    GestureDir::L
+                }
+            } else {
+                // Y axis
+                if movement.1 > 0 {
+                    GestureDir::D
+                } else {
+                    GestureDir::U
+                }
+            }
+        });
+        match gesture.strokes.last_mut() {
+            Some(last) if dir.is_none_or(|dir| dir == last.dir) => {
+                last.length += along(last.dir, movement);
+                last.updated = now;
+            }
+            _ => {
+                if let Some(dir) = dir {
+                    // the clock starts with the stroke's first significant
+                    // movement, not with the gesture or the previous stroke,
+                    // which may have been followed by a pause.
+                    gesture.strokes.push(StrokeProgress {
+                        dir,
+                        length: along(dir, movement),
+                        started: now,
+                        updated: now,
+                    });
+                    gesture.update_candidates(config);
+                }
+            }
+        }
+        if !significant {
+            return;
+        }
+
+        let cancel = if config
+            .max_duration
+            .is_some_and(|max| gesture.started.elapsed() > max)
+        {
+            Some("max_duration")
+        } else if config
+            .max_strokes
+            .is_some_and(|max| gesture.strokes.len() > max)
+        {
+            Some("max_strokes")
+        } else if let Some(radius) = config.cancel_on_return {
+            let radius = i64::from(radius);
+            let away = gesture.pos.0.abs() > radius || gesture.pos.1.abs() > radius;
+            if away {
+                gesture.left_origin = true;
+                None
+            } else {
+                gesture.left_origin.then_some("cancel_on_return")
+            }
+        } else {
+            None
+        };
+        if let Some(reason) = cancel {
+            self.cancel_gesture(reason);
+        }
+    }
+
+    /// Feed movement into the continuous gesture, if one is active.
+    pub fn continuous_gesture_move(&mut self, movement: (i32, i32)) -> Vec<InputEvent> {
+        let Some(gesture) = &mut self.continuous_gesture else {
+            return vec![];
+        };
+        let actions = gesture.accumulate(movement);
+        actions
+            .iter()
+            .flat_map(|action| {
+                tracing::debug!(?action, "Continuous gesture step");
+                action
+                    .run(self, Direction::Down, "Continuous gesture down")
+                    .into_iter()
+                    .chain(action.run(self, Direction::Up, "Continuous gesture up"))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn record_shape(&mut self, name: &str) {
+        match self.gesture.take() {
+            Some(gesture) if !gesture.cancelled => self.shapes.record(name, &gesture.trail),
+            _ => {}
+        }
+    }
+
+    /// Start recording a gesture, matched against `gestures` unless it's
+    /// recording a shape.
+    pub fn start_gesture(
+        &mut self,
+        gestures: Option<&Gestures>,
+    ) -> impl IntoIterator<Item = InputEvent> + use<> {
+        self.gesture = Some(GestureState::new(gestures, &self.gesture_config));
         std::iter::empty()
     }
 
@@ -225,18 +738,25 @@ impl State {
         &mut self,
         config: &Gestures,
     ) -> impl IntoIterator<Item = InputEvent> + use<> {
-        // TODO: too much cloning happening here
-        let Some(gesture_dir) = self.gesture_dir.take() else {
+        if self
+            .gesture_config
+            .max_duration
+            .zip(self.gesture.as_ref())
+            .is_some_and(|(max, gesture)| gesture.started.elapsed() > max)
+        {
+            self.cancel_gesture("max_duration");
+        }
+        let Some(gesture) = self.gesture.take() else {
             return std::iter::empty().left();
         };
-        let key = gesture_dir
-            .iter()
-            .map(|x| format!("{x:?}"))
-            .collect::<Vec<_>>()
-            .join("");
-        tracing::debug!(?key, "Gesture activated");
-        if let Some(action) = config.get(&key) {
-            tracing::debug!(?action, "Gesture action");
+        if gesture.cancelled {
+            return std::iter::empty().left();
+        }
+        let shape_score = |name: &str| self.shapes.score(name, &gesture.trail);
+        let strokes = gesture.strokes(&self.gesture_config, true);
+        if let Some((key, action)) = config.find(&strokes, shape_score, &self.gesture_config) {
+            tracing::debug!(?strokes, key, ?action, "Gesture activated");
+            self.last_gesture = Some(GestureResult::Executed);
             action
                 .run(self, Direction::Down, "Gesture down")
                 .into_iter()
@@ -245,24 +765,532 @@ impl State {
                 .into_iter()
                 .right()
         } else {
+            tracing::debug!(?strokes, "Gesture unmatched");
+            self.last_gesture = Some(GestureResult::Unmatched);
             std::iter::empty().left()
         }
     }
 }
 
+const MAX_TRAIL_POINTS: usize = 4096;
+
+pub struct GestureState {
+    pub gestures: Option<Gestures>,
+    strokes: Vec<StrokeProgress>,
+    started: Instant,
+    /// Position relative to where the gesture started.
+    pos: (i64, i64),
+    /// Positions since the start, for shape matching.
+    pub trail: Vec<Point>,
+    /// Whether the pointer went further than `cancel_on_return` from the start.
+    left_origin: bool,
+    pub cancelled: bool,
+    /// Published trail and candidates, updated as the gesture progresses
+    /// rather than for every published state.
+    pub published_trail: Vec<(f32, f32)>,
+    pub candidates: Vec<GestureCandidate>,
+}
+
+impl GestureState {
+    fn new(gestures: Option<&Gestures>, config: &GestureConfig) -> Self {
+        let mut gesture = Self {
+            gestures: gestures.cloned(),
+            strokes: vec![],
+            started: Instant::now(),
+            pos: (0, 0),
+            trail: vec![(0.0, 0.0)],
+            left_origin: false,
+            cancelled: false,
+            published_trail: vec![],
+            candidates: vec![],
+        };
+        gesture.update_candidates(config);
+        gesture
+    }
+
+    /// Only the strokes before the last one are classified, so the candidates
+    /// only change with a new stroke.
+    fn update_candidates(&mut self, config: &GestureConfig) {
+        let strokes = self.strokes(config, false);
+        self.candidates = self
+            .gestures
+            .iter()
+            .flat_map(|x| x.reachable(strokes.clone()))
+            .map(|(key, action)| GestureCandidate {
+                key: key.to_owned(),
+                action: action.to_owned(),
+            })
+            .collect();
+    }
+
+    pub fn dirs(&self) -> Vec<GestureDir> {
+        self.strokes.iter().map(|x| x.dir).collect()
+    }
+
+    /// Classify the strokes. Unless `finished`, the last stroke may still
+    /// grow, so it's left unclassified.
+    pub fn strokes(&self, config: &GestureConfig, finished: bool) -> Vec<Stroke> {
+        let in_progress = if finished {
+            None
+        } else {
+            self.strokes.len().checked_sub(1)
+        };
+        self.strokes
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                let known = Some(i) != in_progress;
+                let duration = (x.updated - x.started).as_secs_f64();
+                let speed = if duration > 0.0 {
+                    x.length as f64 / duration
+                } else {
+                    f64::INFINITY
+                };
+                Stroke {
+                    dir: x.dir,
+                    length: config.long_stroke.filter(|_| known).map(|long| {
+                        if x.length >= u64::from(long) {
+                            Length::Long
+                        } else {
+                            Length::Short
+                        }
+                    }),
+                    speed: config.fast_stroke.filter(|_| known).map(|fast| {
+                        if speed >= fast {
+                            Speed::Fast
+                        } else {
+                            Speed::Slow
+                        }
+                    }),
+                }
+            })
+            .collect()
+    }
+}
+
+struct StrokeProgress {
+    dir: GestureDir,
+    /// Movement in `dir`, in counts.
+    length: u64,
+    started: Instant,
+    updated: Instant,
+}
+
+/// Movement along `dir`, ignoring movement the other way.
+fn along(dir: GestureDir, movement: (i32, i32)) -> u64 {
+    let value = match dir {
+        GestureDir::R => movement.0,
+        GestureDir::L => -movement.0,
+        GestureDir::D => movement.1,
+        GestureDir::U => -movement.1,
+    };
+    value.max(0) as u64
+}
+
+pub struct ContinuousGestureState {
+    config: Box<ContinuousGesture>,
+    /// Movement not yet turned into steps, per axis. Wider than the steps, so
+    /// they always fit.
+    x: i64,
+    y: i64,
+}
+
+impl ContinuousGestureState {
+    pub fn new(config: Box<ContinuousGesture>) -> Self {
+        Self { config, x: 0, y: 0 }
+    }
+
+    fn accumulate(&mut self, movement: (i32, i32)) -> Vec<Action> {
+        let mut actions = vec![];
+        if movement.0.abs() > movement.1.abs() {
+            Self::step(
+                &mut self.x,
+                movement.0,
+                &self.config.right,
+                &self.config.left,
+                &mut actions,
+            );
+        } else {
+            Self::step(
+                &mut self.y,
+                movement.1,
+                &self.config.down,
+                &self.config.up,
+                &mut actions,
+            );
+        }
+        actions
+    }
+
+    /// Moving back the other way steps in the opposite direction, or just
+    /// unwinds the accumulated movement if there is no action for it.
+    fn step(
+        acc: &mut i64,
+        delta: i32,
+        pos: &Option<GestureStep>,
+        neg: &Option<GestureStep>,
+        actions: &mut Vec<Action>,
+    ) {
+        *acc += i64::from(delta);
+        match pos {
+            Some(pos) => {
+                let step = i64::from(pos.step.get());
+                while *acc >= step {
+                    *acc -= step;
+                    actions.push(pos.action.clone());
+                }
+            }
+            None => *acc = (*acc).min(0),
+        }
+        match neg {
+            Some(neg) => {
+                let step = i64::from(neg.step.get());
+                while *acc <= -step {
+                    *acc += step;
+                    actions.push(neg.action.clone());
+                }
+            }
+            None => *acc = (*acc).max(0),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SequenceState {
+    running: Option<RunningSequence>,
+}
+
+struct RunningSequence {
+    sequence: Sequence,
+    /// Next step to run.
+    pos: usize,
+    /// Completed runs.
+    runs: u32,
+    /// Set while waiting on a delay step.
+    deadline: Option<tokio::time::Instant>,
+    /// Keys pressed by the sequence, and not yet released.
+    held: BTreeSet<KeyCode>,
+}
+
+impl SequenceState {
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// Stop the running sequence, if any, releasing keys it holds.
+    pub fn cancel(&mut self) -> Vec<InputEvent> {
+        let Some(running) = self.running.take() else {
+            return vec![];
+        };
+        tracing::debug!(
+            pos = running.pos,
+            runs = running.runs,
+            "Cancelling sequence"
+        );
+        running
+            .held
+            .into_iter()
+            .map(|key| InputEvent::new(EventType::KEY.0, key.0, 0))
+            .collect()
+    }
+
+    pub async fn wait(&mut self) {
+        // same reasoning as in MetaDown::wait: the deadline can't change while
+        // we're borrowed.
+        match &self.running {
+            Some(RunningSequence {
+                deadline: Some(deadline),
+                ..
+            }) => tokio::time::sleep_until(*deadline).await,
+            _ => std::future::pending().await,
+        }
+    }
+}
+
+impl State {
+    pub fn start_sequence(&mut self, sequence: &Sequence) -> Vec<InputEvent> {
+        let mut evts = vec![];
+        if self.sequence.is_running() {
+            tracing::debug!(on_busy = ?sequence.on_busy, "Sequence already running");
+            match sequence.on_busy {
+                SequenceConflict::Restart => evts = self.sequence.cancel(),
+                SequenceConflict::Ignore => return evts,
+                SequenceConflict::Stop => return self.sequence.cancel(),
+            }
+        }
+        self.sequence.running = Some(RunningSequence {
+            sequence: sequence.clone(),
+            pos: 0,
+            runs: 0,
+            deadline: None,
+            held: BTreeSet::new(),
+        });
+        evts.extend(self.advance_sequence());
+        evts
+    }
+
+    /// Run sequence steps up to the next delay, or to the end.
+    pub fn advance_sequence(&mut self) -> Vec<InputEvent> {
+        let Some(mut running) = self.sequence.running.take() else {
+            return vec![];
+        };
+        running.deadline = None;
+        let mut evts = vec![];
+        loop {
+            let Some(step) = running.sequence.steps.get(running.pos) else {
+                running.runs += 1;
+                let repeat = running.sequence.repeat;
+                if repeat != 0 && running.runs >= repeat {
+                    tracing::debug!(runs = running.runs, "Sequence finished");
+                } else if repeat == 0 && !running.sequence.waits() {
+                    // otherwise we'd spin forever
+                    tracing::warn!("Endless sequence without delays, stopping");
+                } else {
+                    running.pos = 0;
+                    continue;
+                }
+                evts.extend(running.held.iter().map(|key| {
+                    tracing::debug!(?key, "Releasing key held by finished sequence");
+                    InputEvent::new(EventType::KEY.0, key.0, 0)
+                }));
+                return evts;
+            };
+            running.pos += 1;
+            match step {
+                SequenceStep::Down(key) => {
+                    running.held.insert(*key);
+                    evts.push(InputEvent::new(EventType::KEY.0, key.0, 1));
+                }
+                SequenceStep::Up(key) => {
+                    running.held.remove(key);
+                    evts.push(InputEvent::new(EventType::KEY.0, key.0, 0));
+                }
+                SequenceStep::Delay(delay) => {
+                    tracing::trace!(?delay, "Sequence waiting");
+                    running.deadline = Some(tokio::time::Instant::now() + *delay);
+                    self.sequence.running = Some(running);
+                    return evts;
+                }
+                SequenceStep::Action(action) => {
+                    evts.extend(action.run(self, Direction::Down, "Sequence step"));
+                    evts.extend(action.run(self, Direction::Up, "Sequence step"));
+                    if self.sequence.is_running() {
+                        tracing::warn!("Nested sequences aren't supported, cancelling inner one");
+                        evts.extend(self.sequence.cancel());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct AutofireState {
+    /// Minimum interval between clicks.
+    pub min_interval: Duration,
+    active: BTreeMap<KeyCode, RunningAutofire>,
+}
+
+struct RunningAutofire {
+    interval: Duration,
+    /// Whether the button is logically pressed.
+    pressed: bool,
+    /// When to toggle `pressed` next.
+    deadline: tokio::time::Instant,
+    next_press: tokio::time::Instant,
+}
+
+impl AutofireState {
+    pub fn start(&mut self, config: &Autofire) -> Vec<InputEvent> {
+        let interval = config.interval.max(self.min_interval);
+        let now = tokio::time::Instant::now();
+        let mut evts = self.stop(config.button);
+        self.active.insert(
+            config.button,
+            RunningAutofire {
+                interval,
+                pressed: true,
+                deadline: now + interval / 2,
+                next_press: now + config.delay.max(interval),
+            },
+        );
+        evts.push(InputEvent::new(EventType::KEY.0, config.button.0, 1));
+        evts
+    }
+
+    pub fn stop_all(&mut self) -> Vec<InputEvent> {
+        let buttons = self.active.keys().copied().collect::<Vec<_>>();
+        buttons.into_iter().flat_map(|x| self.stop(x)).collect()
+    }
+
+    pub fn stop(&mut self, button: KeyCode) -> Vec<InputEvent> {
+        match self.active.remove(&button) {
+            Some(RunningAutofire { pressed: true, .. }) => {
+                vec![InputEvent::new(EventType::KEY.0, button.0, 0)]
+            }
+            _ => vec![],
+        }
+    }
+
+    pub async fn wait(&mut self) {
+        match self.active.values().map(|x| x.deadline).min() {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Press or release buttons whose time has come.
+    pub fn tick(&mut self) -> Vec<InputEvent> {
+        let now = tokio::time::Instant::now();
+        let mut evts = vec![];
+        for (button, running) in &mut self.active {
+            if running.deadline > now {
+                continue;
+            }
+            running.pressed = !running.pressed;
+            if running.pressed {
+                running.deadline = now + running.interval / 2;
+                running.next_press = now + running.interval;
+            } else {
+                running.deadline = running.next_press;
+            }
+            tracing::trace!(?button, pressed = running.pressed, "Autofire");
+            evts.push(InputEvent::new(
+                EventType::KEY.0,
+                button.0,
+                running.pressed as i32,
+            ));
+        }
+        evts
+    }
+}
+
+#[derive(Default)]
+pub struct SpeedState {
+    pub levels: Vec<f64>,
+    /// Index into `levels`, if the speed was set from there.
+    pub level: Option<usize>,
+}
+
+impl State {
+    pub fn set_speed_level(&mut self, level: Option<usize>) {
+        self.speed.level = level.filter(|x| *x < self.speed.levels.len());
+        self.slow = self.speed.level.map(|x| self.speed.levels[x]);
+        tracing::debug!(level = ?self.speed.level, slow = ?self.slow, "Speed level set");
+    }
+
+    pub fn cycle_speed(&mut self) {
+        let len = self.speed.levels.len();
+        if len == 0 {
+            tracing::warn!("No speed levels configured");
+            return;
+        }
+        let next = match self.speed.level {
+            Some(level) => (level + 1) % len,
+            // start from the default speed, if it's in the list.
+            None => self
+                .speed
+                .levels
+                .iter()
+                .position(|x| *x == 1.0)
+                .map_or(0, |x| (x + 1) % len),
+        };
+        self.set_speed_level(Some(next));
+    }
+
+    /// Switch to the closest faster (if `up`) or slower speed level.
+    pub fn step_speed(&mut self, up: bool) {
+        let current = self.slow.unwrap_or(1.0);
+        let next = self
+            .speed
+            .levels
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| if up { **x > current } else { **x < current })
+            .min_by(|(_, a), (_, b)| (*a - current).abs().total_cmp(&(*b - current).abs()))
+            .map(|(idx, _)| idx);
+        if next.is_some() {
+            self.set_speed_level(next);
+        } else {
+            tracing::debug!(?current, up, "No further speed level");
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct ScrollState {
     pub active: bool,
     pub axes: HashMap<RelativeAxisCode, f64>,
+    /// Recent movement per physical axis, for acceleration.
+    velocity: HashMap<RelativeAxisCode, VecDeque<(SystemTime, u32)>>,
+    /// Scroll state from before each `HoldScroll` that is still held.
+    held: Vec<bool>,
 }
 
 impl ScrollState {
+    pub fn set(&mut self, active: bool) {
+        if self.active != active {
+            self.toggle();
+        }
+    }
+
+    /// Activate scrolling until the matching [`Self::release`].
+    pub fn hold(&mut self) {
+        self.held.push(self.active);
+        self.set(true);
+    }
+
+    /// Restore the state from before the hold. Releases without a hold, e.g.
+    /// of a button held since startup, are ignored.
+    pub fn release(&mut self) {
+        if let Some(active) = self.held.pop() {
+            self.set(active);
+        }
+    }
+
+    /// The state without any holds, i.e. the one to persist.
+    pub fn base(&self) -> bool {
+        self.held.first().copied().unwrap_or(self.active)
+    }
+
+    /// Release all holds at once.
+    pub fn release_all(&mut self) {
+        let active = self.base();
+        self.held.clear();
+        self.set(active);
+    }
+
     pub fn toggle(&mut self) {
         self.active = !self.active;
         tracing::debug!(active = ?self.active, "Scroll state toggled");
         if self.active {
             self.axes.clear();
+            self.velocity.clear();
+        }
+    }
+
+    /// Track the movement and get the acceleration multiplier for it.
+    pub fn accelerate(
+        &mut self,
+        axis: RelativeAxisCode,
+        value: i32,
+        time: SystemTime,
+        acceleration: &ScrollAcceleration,
+    ) -> f64 {
+        let samples = self.velocity.entry(axis).or_default();
+        samples.push_back((time, value.unsigned_abs()));
+        while let Some((first, _)) = samples.front()
+            && time
+                .duration_since(*first)
+                .is_ok_and(|x| x > acceleration.window)
+        {
+            samples.pop_front();
         }
+        let counts = samples.iter().map(|(_, x)| u64::from(*x)).sum::<u64>();
+        let velocity = counts as f64 / acceleration.window.as_secs_f64();
+        let multiplier = acceleration.multiplier(velocity);
+        tracing::trace!(?axis, velocity, multiplier, "Scroll acceleration");
+        multiplier
     }
 
     pub fn scroll(&mut self, axis: RelativeAxisCode, value: i32, factor: f64) -> i32 {
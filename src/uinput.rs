@@ -0,0 +1,107 @@
+//! Virtual keyboard with LEDs, which evdev's `VirtualDeviceBuilder` can't set
+//! up.
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+};
+
+use evdev::{AttributeSetRef, EventType, InputEvent, InputId, KeyCode, LedCode};
+use tokio::io::unix::AsyncFd;
+
+const UINPUT_IOCTL_BASE: u8 = b'U';
+nix::ioctl_none!(ui_dev_create, UINPUT_IOCTL_BASE, 1);
+nix::ioctl_write_ptr!(ui_dev_setup, UINPUT_IOCTL_BASE, 3, libc::uinput_setup);
+nix::ioctl_write_int!(ui_set_evbit, UINPUT_IOCTL_BASE, 100);
+nix::ioctl_write_int!(ui_set_keybit, UINPUT_IOCTL_BASE, 101);
+nix::ioctl_write_int!(ui_set_ledbit, UINPUT_IOCTL_BASE, 105);
+
+/// The device is destroyed once the file is closed.
+pub struct LedKeyboard {
+    file: AsyncFd<File>,
+}
+
+impl LedKeyboard {
+    pub fn new(
+        name: &str,
+        id: InputId,
+        keys: Option<&AttributeSetRef<KeyCode>>,
+        leds: Option<&AttributeSetRef<LedCode>>,
+    ) -> std::io::Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/uinput")?;
+        let fd = file.as_raw_fd();
+        let mut setup = libc::uinput_setup {
+            id: libc::input_id {
+                bustype: id.bus_type().0,
+                vendor: id.vendor(),
+                product: id.product(),
+                version: id.version(),
+            },
+            name: [0; libc::UINPUT_MAX_NAME_SIZE],
+            ff_effects_max: 0,
+        };
+        // keep the terminating nul
+        for (dst, src) in setup.name[..libc::UINPUT_MAX_NAME_SIZE - 1]
+            .iter_mut()
+            .zip(name.bytes())
+        {
+            *dst = src as libc::c_char;
+        }
+        // SAFETY: fd is an open uinput device, setup outlives the call.
+        unsafe {
+            if let Some(keys) = keys {
+                ui_set_evbit(fd, EventType::KEY.0.into())?;
+                for key in keys.iter() {
+                    ui_set_keybit(fd, key.0.into())?;
+                }
+            }
+            if let Some(leds) = leds {
+                ui_set_evbit(fd, EventType::LED.0.into())?;
+                for led in leds.iter() {
+                    ui_set_ledbit(fd, led.0.into())?;
+                }
+            }
+            ui_dev_setup(fd, &setup)?;
+            ui_dev_create(fd)?;
+        }
+        // SAFETY: the AsyncFd owns the file, so the fd stays open and the
+        // same until it is dropped.
+        let file = unsafe { AsyncFd::register(file)? };
+        Ok(Self { file })
+    }
+
+    /// Emit the events as one frame.
+    pub fn emit(&mut self, evts: &[InputEvent]) -> std::io::Result<()> {
+        let syn = InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0);
+        let raw = evts
+            .iter()
+            .chain([&syn])
+            .map(|x| *x.as_ref())
+            .collect::<Vec<libc::input_event>>();
+        // SAFETY: input_event is plain old data.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(raw.as_ptr().cast::<u8>(), std::mem::size_of_val(&*raw))
+        };
+        self.file.get_ref().write_all(bytes)
+    }
+
+    /// Next event sent to the device, like LED changes.
+    pub async fn next_event(&mut self) -> std::io::Result<InputEvent> {
+        let mut buf = [0; size_of::<libc::input_event>()];
+        loop {
+            let mut guard = self.file.readable().await?;
+            match guard.try_io(|file| file.get_ref().read_exact(&mut buf)) {
+                Ok(res) => res?,
+                Err(_would_block) => continue,
+            }
+            // SAFETY: the kernel wrote a whole input_event.
+            let raw = unsafe { std::ptr::read_unaligned(buf.as_ptr().cast::<libc::input_event>()) };
+            return Ok(raw.into());
+        }
+    }
+}